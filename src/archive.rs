@@ -0,0 +1,217 @@
+use std::{
+    fs::{self, File},
+    io::{self, BufReader, Read},
+    path::{Component, Path},
+};
+
+use anyhow::{bail, Context, Result};
+
+/// The archive formats we know how to extract.
+#[derive(Debug, Clone, Copy, Eq, PartialEq)]
+pub enum ArchiveKind {
+    Tar,
+    TarGz,
+    TarZst,
+    Zip,
+}
+
+impl ArchiveKind {
+    /// Determine the kind of archive from a file name.
+    pub fn from_name(name: &str) -> Option<Self> {
+        let name = name.to_ascii_lowercase();
+
+        if name.ends_with(".tar.gz") || name.ends_with(".tgz") {
+            Some(Self::TarGz)
+        } else if name.ends_with(".tar.zst") || name.ends_with(".tzst") {
+            Some(Self::TarZst)
+        } else if name.ends_with(".tar") {
+            Some(Self::Tar)
+        } else if name.ends_with(".zip") {
+            Some(Self::Zip)
+        } else {
+            None
+        }
+    }
+}
+
+/// Extract `archive` into `destination`.
+///
+/// Fails without writing the offending entry if any entry would end up
+/// outside of `destination`, or would replace something other than a
+/// directory that's already there (such as the same file from another
+/// archive).
+pub fn extract(kind: ArchiveKind, archive: &Path, destination: &Path) -> Result<()> {
+    let file = BufReader::new(
+        File::open(archive)
+            .with_context(|| format!("Could not open '{}'", archive.to_string_lossy()))?,
+    );
+
+    match kind {
+        ArchiveKind::Tar => extract_tar(file, destination),
+        ArchiveKind::TarGz => extract_tar(flate2::read::GzDecoder::new(file), destination),
+        ArchiveKind::TarZst => extract_tar(zstd::Decoder::new(file)?, destination),
+        ArchiveKind::Zip => extract_zip(file, destination),
+    }
+    .with_context(|| format!("Failed to extract '{}'", archive.to_string_lossy()))
+}
+
+fn extract_tar<R: Read>(reader: R, destination: &Path) -> Result<()> {
+    let mut archive = tar::Archive::new(reader);
+    archive.set_overwrite(false);
+
+    for entry in archive.entries()? {
+        let mut entry = entry?;
+        let path = entry.path()?.into_owned();
+        ensure_enclosed(&path)?;
+
+        // A link pointing outside the destination is just as bad as a file
+        // written there, since a later task might write through it. We can't
+        // just resolve the target textually, since it may go through other
+        // links (`l1 -> .` followed by `l1/l2 -> ..`), so any target that
+        // climbs at all is refused. Links that only descend can't escape.
+        if let Some(target) = entry.link_name()? {
+            if target.is_absolute() || !is_descending(&target) {
+                bail!(
+                    "Refusing to extract '{}', which links outside the destination",
+                    path.to_string_lossy()
+                );
+            }
+        }
+
+        if !entry.header().entry_type().is_dir() {
+            ensure_new(destination, &path)?;
+        }
+
+        if !entry.unpack_in(destination)? {
+            bail!(
+                "Refusing to extract '{}', which is outside the destination",
+                path.to_string_lossy()
+            );
+        }
+    }
+
+    Ok(())
+}
+
+fn extract_zip<R: Read + io::Seek>(reader: R, destination: &Path) -> Result<()> {
+    let mut archive = zip::ZipArchive::new(reader)?;
+
+    for i in 0..archive.len() {
+        let mut entry = archive.by_index(i)?;
+        let Some(path) = entry.enclosed_name().map(|p| p.to_owned()) else {
+            bail!(
+                "Refusing to extract '{}', which is outside the destination",
+                entry.name()
+            );
+        };
+        ensure_enclosed(&path)?;
+
+        let target = destination.join(&path);
+        if entry.is_dir() {
+            fs::create_dir_all(&target)?;
+            continue;
+        }
+
+        ensure_new(destination, &path)?;
+
+        if let Some(parent) = target.parent() {
+            fs::create_dir_all(parent)?;
+        }
+
+        let mut out = File::create(&target)
+            .with_context(|| format!("Could not create '{}'", target.to_string_lossy()))?;
+        io::copy(&mut entry, &mut out)?;
+
+        #[cfg(unix)]
+        if let Some(mode) = entry.unix_mode() {
+            use std::os::unix::fs::PermissionsExt;
+            fs::set_permissions(&target, fs::Permissions::from_mode(mode & 0o777))?;
+        }
+    }
+
+    Ok(())
+}
+
+/// Determine if a relative path only ever descends from the directory it's
+/// joined onto.
+fn is_descending(path: &Path) -> bool {
+    path.components()
+        .all(|c| matches!(c, Component::Normal(_) | Component::CurDir))
+}
+
+fn ensure_enclosed(path: &Path) -> Result<()> {
+    if path.is_absolute() || path.components().any(|c| c == Component::ParentDir) {
+        bail!(
+            "Refusing to extract '{}', which is outside the destination",
+            path.to_string_lossy()
+        );
+    }
+
+    Ok(())
+}
+
+/// Fail if an entry at `path` would replace something already extracted.
+fn ensure_new(destination: &Path, path: &Path) -> Result<()> {
+    if fs::symlink_metadata(destination.join(path)).is_ok() {
+        bail!(
+            "Refusing to extract '{}', which has already been extracted",
+            path.to_string_lossy()
+        );
+    }
+
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn tar_with_links(links: &[(&str, &str)]) -> Vec<u8> {
+        let mut builder = tar::Builder::new(Vec::new());
+        for (path, target) in links {
+            let mut header = tar::Header::new_gnu();
+            header.set_entry_type(tar::EntryType::Symlink);
+            header.set_size(0);
+            builder
+                .append_link(&mut header, path, target)
+                .expect("failed to build archive");
+        }
+
+        builder.into_inner().expect("failed to build archive")
+    }
+
+    #[test]
+    fn rejects_chained_symlink_escape() {
+        let destination = tempfile::tempdir().unwrap();
+        let archive = tar_with_links(&[("l1", "."), ("l1/l2", "..")]);
+
+        assert!(extract_tar(archive.as_slice(), destination.path()).is_err());
+        assert!(fs::symlink_metadata(destination.path().join("l2")).is_err());
+    }
+
+    #[test]
+    fn rejects_absolute_symlink() {
+        let destination = tempfile::tempdir().unwrap();
+        let archive = tar_with_links(&[("evil", "/etc")]);
+
+        assert!(extract_tar(archive.as_slice(), destination.path()).is_err());
+    }
+
+    #[test]
+    fn allows_descending_symlink() {
+        let destination = tempfile::tempdir().unwrap();
+        let archive = tar_with_links(&[("current", "./releases/1.2.3")]);
+
+        extract_tar(archive.as_slice(), destination.path()).unwrap();
+        assert!(fs::symlink_metadata(destination.path().join("current")).is_ok());
+    }
+
+    #[test]
+    fn rejects_duplicate_across_archives() {
+        let destination = tempfile::tempdir().unwrap();
+        let archive = tar_with_links(&[("current", "./releases/1.2.3")]);
+
+        extract_tar(archive.as_slice(), destination.path()).unwrap();
+        assert!(extract_tar(archive.as_slice(), destination.path()).is_err());
+    }
+}
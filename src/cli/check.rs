@@ -1,72 +1,450 @@
-use anyhow::Result;
+use anyhow::{Context, Result};
 use clap::Args;
 use clap_stdin::FileOrStdin;
 use gen_api_wrapper::query::AsyncQuery;
+use globset::{Glob, GlobMatcher};
+use regex::Regex;
 
 use crate::{
-    client::GiteaClient,
-    endpoints::PackagesEndpoint,
-    models::Package,
-    params::{CheckParams, Version},
+    client::{GiteaClient, Page},
+    endpoints::{PackageFilesEndpoint, PackagesEndpoint},
+    models::{files_digest, Package, PackageFile},
+    params::{CheckParams, MissingVersion, Version, VersionOrder},
 };
 
+/// The number of versions to request per page. Gitea caps this at 50 by
+/// default.
+const PAGE_SIZE: u64 = 50;
+
 #[derive(Debug, Clone, Args)]
 pub struct Check {
     params: FileOrStdin<CheckParams>,
 }
 
+/// A package version we might emit, along with its parsed semantic version
+/// if we needed one and whether or not it passed the configured filters.
+#[derive(Debug, Clone)]
+struct Candidate {
+    package: Package,
+    semver: Option<semver::Version>,
+    matches: bool,
+}
+
+/// The versions a check might emit, before looking at their files.
+#[derive(Debug, Clone)]
+struct Selection {
+    /// The versions that pass the filters and come after the previous version,
+    /// oldest first, or newest first when `only_latest` is set.
+    candidates: Vec<Package>,
+    /// Whether the previous version still exists and passes the filters.
+    previous_matches: bool,
+    /// Whether to only emit the first complete candidate, because the previous
+    /// version no longer exists.
+    only_latest: bool,
+}
+
 impl Check {
     pub async fn run(&self) -> Result<()> {
         let params = self.params.clone().into_inner();
         let client = GiteaClient::try_from(&params.source)?;
 
-        let endpoint = PackagesEndpoint::buidler()
+        let packages = Self::fetch_packages(&client, &params).await?;
+
+        let Selection {
+            candidates,
+            previous_matches,
+            only_latest,
+        } = Self::select_candidates(&params, packages)?;
+
+        let required_files = params
+            .source
+            .required_files
+            .iter()
+            .map(|p| {
+                Glob::new(p)
+                    .map(|g| g.compile_matcher())
+                    .with_context(|| format!("Invalid required_files entry '{}'", p))
+            })
+            .collect::<Result<Vec<_>>>()?;
+
+        let needs_files = params.source.track_files
+            || !required_files.is_empty()
+            || params.source.completion_marker.is_some();
+
+        let mut versions: Vec<Version> = Vec::new();
+
+        // When tracking files, the previous version is new again if its set of
+        // files has changed since we last saw it.
+        if let Some(ref previous) = params.version {
+            if params.source.track_files && previous_matches {
+                let files = Self::fetch_files(&client, &params, &previous.version).await?;
+                let digest = files_digest(&files);
+                if previous.digest.as_ref() != Some(&digest) {
+                    versions.push(Version {
+                        version: previous.version.clone(),
+                        digest: Some(digest),
+                    });
+                }
+            }
+        }
+
+        for package in candidates {
+            let files = if needs_files {
+                Self::fetch_files(&client, &params, &package.version).await?
+            } else {
+                Vec::new()
+            };
+
+            if !Self::is_complete(&params, &required_files, &files) {
+                eprintln!(
+                    "Skipping '{}' because it is missing required files",
+                    &package.version
+                );
+                continue;
+            }
+
+            let digest = if params.source.track_files {
+                Some(files_digest(&files))
+            } else {
+                None
+            };
+
+            versions.push(Version {
+                version: package.version,
+                digest,
+            });
+
+            if only_latest {
+                break;
+            }
+        }
+
+        if versions.is_empty() && previous_matches {
+            // we specified version _is_ the latest, so we just return that,
+            // unless it's been filtered out, in which case there's nothing to
+            // return
+            if let Some(previous) = params.version {
+                versions.push(previous);
+            }
+        }
+
+        println!("{}", serde_json::to_string(&versions)?);
+
+        Ok(())
+    }
+
+    /// Filter and order `packages`, and find where the previous version falls
+    /// among them.
+    fn select_candidates(params: &CheckParams, packages: Vec<Package>) -> Result<Selection> {
+        let needs_semver = params.source.order_by == VersionOrder::Semver
+            || params.source.version_constraint.is_some();
+
+        let version_regex = params
+            .source
+            .version_regex
+            .as_deref()
+            .map(Regex::new)
+            .transpose()
+            .context("Invalid version_regex")?;
+
+        let ignore_versions = params
+            .source
+            .ignore_versions
+            .iter()
+            .map(|p| {
+                Regex::new(p).with_context(|| format!("Invalid ignore_versions entry '{}'", p))
+            })
+            .collect::<Result<Vec<_>>>()?;
+
+        let mut candidates: Vec<Candidate> = packages
+            .into_iter()
+            .map(|package| {
+                let semver = if needs_semver {
+                    semver::Version::parse(&package.version).ok()
+                } else {
+                    None
+                };
+
+                let mut matches = true;
+
+                if needs_semver && semver.is_none() {
+                    eprintln!(
+                        "Ignoring '{}' because it is not a valid semantic version",
+                        &package.version
+                    );
+                    matches = false;
+                }
+
+                if let Some(ref constraint) = params.source.version_constraint {
+                    matches &= semver.as_ref().is_some_and(|v| constraint.matches(v));
+                }
+
+                if let Some(ref re) = version_regex {
+                    matches &= re.is_match(&package.version);
+                }
+
+                matches &= !ignore_versions
+                    .iter()
+                    .any(|re| re.is_match(&package.version));
+
+                Candidate {
+                    package,
+                    semver,
+                    matches,
+                }
+            })
+            .collect();
+
+        match params.source.order_by {
+            // we're making the assumption that the ids are never decreasing so
+            // we don't need to actually compare the timestamps
+            VersionOrder::Created => candidates.sort_by_key(|c| c.package.id),
+            VersionOrder::Semver => candidates.sort_by(|a, b| a.semver.cmp(&b.semver)),
+        }
+
+        let mut previous_matches = false;
+        let mut only_latest = false;
+
+        // We locate the previous version before dropping filtered out
+        // candidates so that we still have a cutoff if the previous version no
+        // longer matches the filters.
+        if let Some(ref previous) = params.version {
+            let pos = candidates
+                .iter()
+                .position(|c| c.package.version == previous.version);
+            if let Some(pos) = pos {
+                previous_matches = candidates[pos].matches;
+                candidates.drain(..=pos);
+            } else {
+                // The previous version no longer exists (it was probably
+                // deleted), so we have no cutoff. Emitting everything would
+                // trigger a build for every version, so by default we just
+                // emit the latest one.
+                match params.source.on_missing_version {
+                    MissingVersion::Latest => {
+                        eprintln!(
+                            "Previous version '{}' no longer exists, emitting the latest version",
+                            &previous.version
+                        );
+                        only_latest = true;
+                    }
+                    MissingVersion::Newer => {
+                        eprintln!(
+                            "Previous version '{}' no longer exists, emitting all newer versions",
+                            &previous.version
+                        );
+                        // We can only tell what's newer than a deleted version
+                        // by comparing semantic versions, otherwise everything
+                        // is considered newer.
+                        if params.source.order_by == VersionOrder::Semver {
+                            if let Ok(cutoff) = semver::Version::parse(&previous.version) {
+                                candidates
+                                    .retain(|c| c.semver.as_ref().is_some_and(|v| v > &cutoff));
+                            }
+                        }
+                    }
+                }
+            }
+        }
+
+        candidates.retain(|c| c.matches);
+
+        // When we only want the latest version, walk backwards and stop at the
+        // first complete one so we don't fetch files for every version.
+        if only_latest {
+            candidates.reverse();
+        }
+
+        Ok(Selection {
+            candidates: candidates.into_iter().map(|c| c.package).collect(),
+            previous_matches,
+            only_latest,
+        })
+    }
+
+    /// Determine if a version's files satisfy `required_files` and
+    /// `completion_marker`.
+    fn is_complete(params: &CheckParams, required: &[GlobMatcher], files: &[PackageFile]) -> bool {
+        if let Some(ref marker) = params.source.completion_marker {
+            if !files.iter().any(|f| &f.name == marker) {
+                return false;
+            }
+        }
+
+        required
+            .iter()
+            .all(|glob| files.iter().any(|f| glob.is_match(&f.name)))
+    }
+
+    async fn fetch_files(
+        client: &GiteaClient,
+        params: &CheckParams,
+        version: &str,
+    ) -> Result<Vec<PackageFile>> {
+        let endpoint = PackageFilesEndpoint::buidler()
             .owner(&params.source.owner)
             .package(&params.source.package)
+            .version(version)
             .build()?;
 
-        let mut packages: Vec<Package> = endpoint.query_async(&client).await?;
+        endpoint.query_async(client).await.with_context(|| {
+            format!(
+                "Could not find files for '{}' at '{}'",
+                &params.source.package, version
+            )
+        })
+    }
 
-        // FIXME: We need to paginate this and even then the performance might be
-        // pretty bad if there are tons of versions. For now, let's just get
-        // this working- MCL - 2023-07-29
+    async fn fetch_packages(client: &GiteaClient, params: &CheckParams) -> Result<Vec<Package>> {
+        let previous = params.version.as_ref().map(|v| v.version.as_str());
+        let mut packages: Vec<Package> = Vec::new();
+        let mut page = 1;
+        let mut fetched = 0;
 
-        // We have to filter because the query param matches substrings.
-        // TODO: see if there's an actual syntax to have the query be an exact
-        // match so we don't have to filter this out. - MCL - 2023-07-29
-        packages.retain(|p| p.name == params.source.package);
+        // gitea lists package versions newest first, so once we've seen the
+        // previous version, everything else we could fetch is older than it
+        // and we can stop paginating. This doesn't hold when ordering by
+        // semver, as an older version could have been published later.
+        let stop_at_previous = params.source.order_by == VersionOrder::Created;
 
-        if let Some(ref previous) = params.version {
-            let pos = packages.iter().position(|p| p.version == previous.version);
-            if let Some(pos) = pos {
-                let cutoff_id = packages[pos].id;
+        loop {
+            let endpoint = PackagesEndpoint::buidler()
+                .owner(&params.source.owner)
+                .package(&params.source.package)
+                .page(page)
+                .limit(PAGE_SIZE)
+                .build()?;
 
-                packages.retain(|p| p.id > cutoff_id);
+            let result: Page<Package> = client
+                .query_page(&endpoint)
+                .await
+                .with_context(|| format!("Failed to fetch page {} of packages", page))?;
 
-                if packages.is_empty() {
-                    // we specified version _is_ the latest, so we just return
-                    // that
-                    let versions = vec![previous];
-                    println!("{}", serde_json::to_string(&versions)?);
+            fetched += result.items.len() as u64;
+            let has_next = result.has_next(fetched, PAGE_SIZE);
 
-                    return Ok(());
+            let mut found_previous = false;
+            // We have to filter because the query param matches substrings.
+            // TODO: see if there's an actual syntax to have the query be an exact
+            // match so we don't have to filter this out. - MCL - 2023-07-29
+            for package in result.items {
+                if package.name != params.source.package {
+                    continue;
                 }
+
+                if previous == Some(package.version.as_str()) {
+                    found_previous = true;
+                }
+
+                packages.push(package);
             }
+
+            if (stop_at_previous && found_previous) || !has_next {
+                break;
+            }
+
+            page += 1;
         }
 
-        // we're making the assumption that the ids are never decreasing so we
-        // don't need to actually compare the timestamps
-        packages.sort_by(|a, b| a.id.cmp(&b.id));
+        Ok(packages)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use serde_json::json;
+
+    use super::*;
 
-        let versions: Vec<Version> = packages
+    const VERSIONS: &[&str] = &["1.0.0", "1.1.0-rc.1", "1.1.0", "1.2.0", "1.3.0-rc.1"];
+
+    fn params(previous: &str, source: serde_json::Value) -> CheckParams {
+        let mut base = json!({
+            "uri": "https://gitea.example.com",
+            "owner": "me",
+            "token": "token",
+            "package": "app",
+            "ignore_versions": ["-rc"],
+        });
+        base.as_object_mut()
+            .unwrap()
+            .extend(source.as_object().unwrap().clone());
+
+        serde_json::from_value(json!({
+            "source": base,
+            "version": { "version": previous },
+        }))
+        .unwrap()
+    }
+
+    fn packages() -> Vec<Package> {
+        VERSIONS
             .iter()
-            .map(|p| Version {
-                version: p.version.clone(),
+            .enumerate()
+            .map(|(id, version)| Package {
+                id: id as u64,
+                version: version.to_string(),
+                name: "app".to_string(),
+                html_url: None,
+                creator: None,
+                created_at: None,
             })
+            .collect()
+    }
+
+    fn select(params: &CheckParams) -> (Vec<String>, Selection) {
+        let selection = Check::select_candidates(params, packages()).unwrap();
+        let versions = selection
+            .candidates
+            .iter()
+            .map(|p| p.version.clone())
             .collect();
+        (versions, selection)
+    }
 
-        println!("{}", serde_json::to_string(&versions)?);
+    #[test]
+    fn filtered_out_previous_still_cuts_off_newer_matches() {
+        let (versions, selection) = select(&params("1.1.0-rc.1", json!({})));
 
-        Ok(())
+        assert_eq!(versions, ["1.1.0", "1.2.0"]);
+        assert!(!selection.previous_matches);
+        assert!(!selection.only_latest);
+    }
+
+    #[test]
+    fn filtered_out_previous_without_newer_matches_emits_nothing() {
+        let (versions, selection) = select(&params("1.3.0-rc.1", json!({})));
+
+        assert!(versions.is_empty());
+        assert!(!selection.previous_matches);
+    }
+
+    #[test]
+    fn matching_previous_that_is_latest_is_emitted_again() {
+        let (versions, selection) = select(&params("1.2.0", json!({})));
+
+        assert!(versions.is_empty());
+        assert!(selection.previous_matches);
+    }
+
+    #[test]
+    fn missing_previous_emits_latest() {
+        let (versions, selection) = select(&params("1.0.5", json!({})));
+
+        assert_eq!(versions, ["1.2.0", "1.1.0", "1.0.0"]);
+        assert!(selection.only_latest);
+        assert!(!selection.previous_matches);
+    }
+
+    #[test]
+    fn missing_previous_emits_newer() {
+        let (versions, selection) = select(&params(
+            "1.0.5",
+            json!({ "order_by": "semver", "on_missing_version": "newer" }),
+        ));
+
+        assert_eq!(versions, ["1.1.0", "1.2.0"]);
+        assert!(!selection.only_latest);
+        assert!(!selection.previous_matches);
     }
 }
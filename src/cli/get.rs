@@ -1,17 +1,48 @@
-use std::path::PathBuf;
+use std::{
+    collections::HashSet,
+    fs,
+    path::{Path, PathBuf},
+    sync::Mutex,
+};
 
-use anyhow::{Context, Result};
+use anyhow::{anyhow, bail, Context, Result};
 use clap::Args;
 use clap_stdin::FileOrStdin;
-use gen_api_wrapper::query::AsyncQuery;
+use ed25519_dalek::VerifyingKey;
+use futures_util::{stream, TryStreamExt};
+use gen_api_wrapper::{client::RestClient, endpoint_prelude::Endpoint, query::AsyncQuery};
+use globset::{Glob, GlobSet, GlobSetBuilder};
+use serde::Serialize;
 
+use super::fetch_package;
 use crate::{
+    archive::{self, ArchiveKind},
     client::GiteaClient,
     endpoints::{PackageFileEndpoint, PackageFilesEndpoint},
-    models::PackageFile,
-    params::{GetOutput, GetParams},
+    models::{Package, PackageFile},
+    names::{sanitize_file_name, validate_file_name},
+    params::{GetOutput, GetParams, GetStepParams, MetadataField, Unpack},
+    signing::{self, signature_name, Statement, SIGNATURE_EXTENSION},
 };
 
+/// Where metadata files go when `metadata_subdir` is set.
+const METADATA_SUBDIR: &str = ".metadata";
+
+/// The metadata files we might write into the destination.
+const METADATA_FILES: &[&str] = &["version", "package", "created_at", "files.json"];
+
+/// An entry in `files.json`.
+#[derive(Debug, Clone, Serialize)]
+struct FileMetadata<'a> {
+    name: &'a str,
+    size: Option<u64>,
+    md5: Option<&'a str>,
+    sha1: Option<&'a str>,
+    sha256: Option<&'a str>,
+    sha512: Option<&'a str>,
+    url: String,
+}
+
 #[derive(Debug, Clone, Args)]
 pub struct Get {
     destination: PathBuf,
@@ -29,16 +60,218 @@ impl Get {
             .version(&params.version.version)
             .build()?;
 
-        let files: Vec<PackageFile> = endpoint.query_async(&client).await.with_context(|| {
-            format!(
-                "Could not find files for '{}' at '{}'",
-                &params.source.package, &self.params.version.version
-            )
-        })?;
+        let mut files: Vec<PackageFile> =
+            endpoint.query_async(&client).await.with_context(|| {
+                format!(
+                    "Could not find files for '{}' at '{}'",
+                    &params.source.package, &self.params.version.version
+                )
+            })?;
+
+        let public_key = params
+            .source
+            .public_key
+            .as_deref()
+            .map(signing::parse_public_key)
+            .transpose()?;
+
+        // signatures are looked up in the whole version, not just the files
+        // we were asked for
+        let all_names: HashSet<String> = files.iter().map(|f| f.name.clone()).collect();
+
+        Self::select_files(&params.params, &mut files)?;
+        let names = Self::local_names(&params.params, &files)?;
+
+        let verify = match public_key {
+            Some(ref key) => Some((key, Self::signed_files(&params, &files, &all_names)?)),
+            None => None,
+        };
+
+        if !params.params.metadata_subdir && !params.params.skip_download {
+            if let Some(name) = names.iter().find(|n| METADATA_FILES.contains(&n.as_str())) {
+                bail!(
+                    "'{}' would be overwritten by the version metadata, set metadata_subdir to avoid this",
+                    name
+                );
+            }
+        }
+
+        // Listing the files already confirmed the version exists, which is all
+        // we need to do if we're not downloading anything.
+        if params.params.skip_download {
+            eprintln!("Skipping download of {} file(s)", files.len());
+        } else {
+            self.download(&client, &params, &files, &names, verify)
+                .await?;
+
+            // the archives were complete, but whatever we extracted might not
+            // be, so don't leave any of it behind
+            if let Err(e) = self.unpack(&params.params, &names) {
+                remove_all(names.iter().map(|name| self.destination.join(name)));
+                return Err(e);
+            }
+        }
+
+        let package = fetch_package(&client, &params.source, &params.version.version).await;
+        self.write_metadata(&client, &params, &files, package.as_ref())
+            .context("Failed to write version metadata")?;
+
+        let output = GetOutput::from(&self.params.version)
+            .with_metadata(MetadataField::describe(package.as_ref(), &files));
+        println!("{}", serde_json::to_string(&output)?);
+
+        Ok(())
+    }
+
+    /// Download each file to the destination, stopping everything as soon as
+    /// one of them fails.
+    async fn download(
+        &self,
+        client: &GiteaClient,
+        params: &GetParams,
+        files: &[PackageFile],
+        names: &[String],
+        verify: Option<(&VerifyingKey, HashSet<&str>)>,
+    ) -> Result<()> {
+        let verify = verify.as_ref();
+        let downloaded: Mutex<Vec<PathBuf>> = Mutex::new(Vec::new());
+        let result = stream::iter(files.iter().zip(names.iter()).map(Ok))
+            .try_for_each_concurrent(params.source.concurrency(), |(file, name)| {
+                let downloaded = &downloaded;
+                async move {
+                    eprintln!("Fetching {}", &file.name);
+                    let endpoint = PackageFileEndpoint::buidler()
+                        .owner(&params.source.owner)
+                        .package(&params.source.package)
+                        .version(&params.version.version)
+                        .file(&file.name)
+                        .build()?;
+
+                    let (path, digest) = client
+                        .download(&self.destination, name, &endpoint, file)
+                        .await
+                        .with_context(|| format!("Failed downloading '{}'", &file.name))?;
+
+                    downloaded.lock().unwrap().push(path);
+
+                    if let Some((key, signed)) = verify {
+                        if signed.contains(file.name.as_str()) {
+                            Self::verify_signature(client, params, key, &file.name, &digest)
+                                .await?;
+                            eprintln!("Verified signature for {}", &file.name);
+                        }
+                    }
+                    eprintln!("Fetched {}", &file.name);
+                    Ok::<_, anyhow::Error>(())
+                }
+            })
+            .await;
+
+        // Any in-progress downloads clean up after themselves, but we don't
+        // want to leave an incomplete set of files behind either.
+        if let Err(e) = result {
+            remove_all(downloaded.into_inner().unwrap());
+            return Err(e);
+        }
+
+        eprintln!("Finished fetching files");
+
+        Ok(())
+    }
+
+    /// Determine which of the selected files must have a valid signature,
+    /// failing if any of them don't have one at all.
+    ///
+    /// Signatures themselves and the completion marker aren't signed.
+    fn signed_files<'a>(
+        params: &GetParams,
+        files: &'a [PackageFile],
+        all_names: &HashSet<String>,
+    ) -> Result<HashSet<&'a str>> {
+        let mut signed = HashSet::new();
+
+        for file in files {
+            let is_signature = file
+                .name
+                .strip_suffix(SIGNATURE_EXTENSION)
+                .is_some_and(|name| all_names.contains(name));
+            if is_signature || params.source.completion_marker.as_ref() == Some(&file.name) {
+                continue;
+            }
+
+            if !all_names.contains(&signature_name(&file.name)) {
+                bail!(
+                    "'{}' has no signature, expected '{}' in version {}",
+                    &file.name,
+                    signature_name(&file.name),
+                    &params.version.version
+                );
+            }
+
+            signed.insert(file.name.as_str());
+        }
+
+        Ok(signed)
+    }
+
+    /// Fetch the signature for `name` and verify it against the sha256 of the
+    /// file we downloaded.
+    async fn verify_signature(
+        client: &GiteaClient,
+        params: &GetParams,
+        key: &VerifyingKey,
+        name: &str,
+        digest: &str,
+    ) -> Result<()> {
+        let endpoint = PackageFileEndpoint::buidler()
+            .owner(&params.source.owner)
+            .package(&params.source.package)
+            .version(&params.version.version)
+            .file(signature_name(name))
+            .build()?;
+
+        let signature = gen_api_wrapper::raw(endpoint)
+            .query_async(client)
+            .await
+            .with_context(|| format!("Could not fetch the signature for '{}'", name))?;
+
+        let statement = Statement {
+            package: &params.source.package,
+            version: &params.version.version,
+            file: name,
+            sha256: digest,
+        };
+        signing::verify(key, &statement, &signature)?;
+
+        Ok(())
+    }
+
+    /// Write files describing the version into the destination, so tasks
+    /// don't have to figure it out themselves.
+    fn write_metadata(
+        &self,
+        client: &GiteaClient,
+        params: &GetParams,
+        files: &[PackageFile],
+        package: Option<&Package>,
+    ) -> Result<()> {
+        let dir = if params.params.metadata_subdir {
+            let dir = self.destination.join(METADATA_SUBDIR);
+            fs::create_dir_all(&dir)?;
+            dir
+        } else {
+            self.destination.clone()
+        };
+
+        fs::write(dir.join("version"), &params.version.version)?;
+        fs::write(dir.join("package"), &params.source.package)?;
+
+        if let Some(created_at) = package.and_then(|p| p.created_at.as_ref()) {
+            fs::write(dir.join("created_at"), created_at)?;
+        }
 
-        // download each file to the specified location
+        let mut described = Vec::with_capacity(files.len());
         for file in files {
-            eprintln!("Fetching {}", &file.name);
             let endpoint = PackageFileEndpoint::buidler()
                 .owner(&params.source.owner)
                 .package(&params.source.package)
@@ -46,19 +279,300 @@ impl Get {
                 .file(&file.name)
                 .build()?;
 
-            client
-                .download(&self.destination, &endpoint)
-                .await
-                .with_context(|| format!("Failed downloading '{}'", &file.name))?;
+            described.push(FileMetadata {
+                name: &file.name,
+                size: file.size,
+                md5: file.md5.as_deref(),
+                sha1: file.sha1.as_deref(),
+                sha256: file.sha256.as_deref(),
+                sha512: file.sha512.as_deref(),
+                url: client.rest_endpoint(&endpoint.endpoint())?.to_string(),
+            });
         }
 
-        eprintln!("Finished fetching files");
+        fs::write(
+            dir.join("files.json"),
+            serde_json::to_vec_pretty(&described)?,
+        )?;
 
-        println!(
-            "{}",
-            serde_json::to_string(&GetOutput::from(&self.params.version))?
-        );
+        Ok(())
+    }
+
+    /// Extract the downloaded archives selected by the `unpack` param.
+    ///
+    /// Everything is extracted into a staging directory first and only moved
+    /// into the destination once every archive extracted successfully. If
+    /// anything fails, whatever was extracted is removed.
+    fn unpack(&self, step: &GetStepParams, names: &[String]) -> Result<()> {
+        let patterns = match step.unpack {
+            Unpack::All(false) => return Ok(()),
+            Unpack::All(true) => None,
+            Unpack::Matching(ref patterns) => {
+                Some(build_globset(patterns).context("Invalid unpack pattern")?)
+            }
+        };
+
+        let mut archives = Vec::new();
+        for name in names {
+            let kind = ArchiveKind::from_name(name);
+            let kind = match (&patterns, kind) {
+                (None, Some(kind)) => kind,
+                (None, None) => continue,
+                (Some(patterns), kind) if patterns.is_match(name) => kind.ok_or_else(|| {
+                    anyhow!("'{}' matches unpack but is not a supported archive", name)
+                })?,
+                (Some(_), _) => continue,
+            };
+
+            archives.push((name, kind));
+        }
+
+        if archives.is_empty() {
+            return Ok(());
+        }
+
+        // removed when dropped, taking any partial extraction with it
+        let staging = tempfile::Builder::new()
+            .prefix(".unpack.")
+            .tempdir_in(&self.destination)
+            .context("Failed to create a staging directory for unpacking")?;
+
+        for (name, kind) in archives.iter() {
+            eprintln!("Extracting {}", name);
+            archive::extract(*kind, &self.destination.join(name), staging.path())?;
+        }
+
+        // the metadata is written after unpacking, so it would silently
+        // replace anything extracted where it goes
+        let metadata_dir = if step.metadata_subdir {
+            Path::new(METADATA_SUBDIR)
+        } else {
+            Path::new("")
+        };
+        for name in METADATA_FILES {
+            let path = metadata_dir.join(name);
+            if fs::symlink_metadata(staging.path().join(&path)).is_ok() {
+                bail!(
+                    "'{}' was extracted from an archive but would be overwritten by the version metadata",
+                    path.to_string_lossy()
+                );
+            }
+        }
+
+        if step.delete_archives {
+            for (name, _) in archives.iter() {
+                let archive = self.destination.join(name);
+                fs::remove_file(&archive).with_context(|| {
+                    format!("Failed to remove archive '{}'", archive.to_string_lossy())
+                })?;
+            }
+        }
+
+        let mut moved = Vec::new();
+        if let Err(e) = move_into(staging.path(), &self.destination, &mut moved) {
+            remove_all(moved);
+            return Err(e).context("Failed to move extracted files into the destination");
+        }
+
+        Ok(())
+    }
+
+    /// Determine the local name for each file, rejecting (or sanitizing) names
+    /// that would escape the destination.
+    fn local_names(step: &GetStepParams, files: &[PackageFile]) -> Result<Vec<String>> {
+        let mut names = Vec::with_capacity(files.len());
+        let mut seen = HashSet::new();
+
+        for file in files {
+            let name = match validate_file_name(&file.name) {
+                Ok(()) => file.name.clone(),
+                Err(_) if step.sanitize_names => {
+                    let name = sanitize_file_name(&file.name);
+                    validate_file_name(&name)?;
+                    eprintln!("Saving '{}' as '{}'", &file.name, &name);
+                    name
+                }
+                Err(e) => return Err(e.into()),
+            };
+
+            if !seen.insert(name.clone()) {
+                bail!(
+                    "Multiple files would be saved as '{}' in the destination",
+                    name
+                );
+            }
+
+            names.push(name);
+        }
+
+        Ok(names)
+    }
+
+    /// Restrict `files` to the ones selected by the `files` and `exclude`
+    /// params, failing if any `files` pattern matches nothing.
+    fn select_files(step: &GetStepParams, files: &mut Vec<PackageFile>) -> Result<()> {
+        let exclude = build_globset(&step.exclude).context("Invalid exclude pattern")?;
+        files.retain(|f| !exclude.is_match(&f.name));
+
+        if step.files.is_empty() {
+            return Ok(());
+        }
+
+        let mut selected = vec![false; files.len()];
+        for pattern in step.files.iter() {
+            let glob = Glob::new(pattern)
+                .with_context(|| format!("Invalid files pattern '{}'", pattern))?
+                .compile_matcher();
+
+            let mut matched = false;
+            for (file, selected) in files.iter().zip(selected.iter_mut()) {
+                if glob.is_match(&file.name) {
+                    *selected = true;
+                    matched = true;
+                }
+            }
+
+            if !matched {
+                bail!("Pattern '{}' did not match any files", pattern);
+            }
+        }
+
+        let mut selected = selected.into_iter();
+        files.retain(|_| selected.next().unwrap_or_default());
 
         Ok(())
     }
 }
+
+/// Move the contents of `source` into `destination`, merging directories that
+/// already exist, and recording each path we move.
+///
+/// Fails rather than replace anything else that already exists, such as a
+/// downloaded file.
+fn move_into(source: &Path, destination: &Path, moved: &mut Vec<PathBuf>) -> Result<()> {
+    for entry in fs::read_dir(source)? {
+        let entry = entry?;
+        let target = destination.join(entry.file_name());
+
+        if entry.file_type()?.is_dir() && target.is_dir() && !target.is_symlink() {
+            move_into(&entry.path(), &target, moved)?;
+            continue;
+        }
+
+        if fs::symlink_metadata(&target).is_ok() {
+            bail!(
+                "Refusing to replace '{}' with an extracted file",
+                target.to_string_lossy()
+            );
+        }
+
+        fs::rename(entry.path(), &target)
+            .with_context(|| format!("Could not move '{}' into place", target.to_string_lossy()))?;
+        moved.push(target);
+    }
+
+    Ok(())
+}
+
+/// Remove files and directories, reporting (but otherwise ignoring) failures.
+fn remove_all(paths: impl IntoIterator<Item = PathBuf>) {
+    for path in paths {
+        eprintln!("Removing '{}'", path.to_string_lossy());
+
+        let result = if path.is_dir() && !path.is_symlink() {
+            fs::remove_dir_all(&path)
+        } else {
+            fs::remove_file(&path)
+        };
+
+        match result {
+            Ok(()) => {}
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => {}
+            Err(e) => eprintln!("Failed to remove '{}': {}", path.to_string_lossy(), e),
+        }
+    }
+}
+
+fn build_globset(patterns: &[String]) -> Result<GlobSet> {
+    let mut builder = GlobSetBuilder::new();
+    for pattern in patterns {
+        builder.add(Glob::new(pattern).with_context(|| format!("Invalid pattern '{}'", pattern))?);
+    }
+
+    Ok(builder.build()?)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn listing(names: &[&str]) -> Vec<PackageFile> {
+        names
+            .iter()
+            .map(|name| PackageFile {
+                name: name.to_string(),
+                size: None,
+                md5: None,
+                sha1: None,
+                sha256: None,
+                sha512: None,
+            })
+            .collect()
+    }
+
+    #[test]
+    fn local_names_rejects_hostile_listing() {
+        let step = GetStepParams::default();
+
+        for name in ["../../etc/x", "/tmp/evil", "a\\b", "..", ""] {
+            let files = listing(&["app", name]);
+            assert!(
+                Get::local_names(&step, &files).is_err(),
+                "'{}' should be rejected",
+                name
+            );
+        }
+    }
+
+    #[test]
+    fn local_names_sanitizes_hostile_listing() {
+        let step = GetStepParams {
+            sanitize_names: true,
+            ..Default::default()
+        };
+
+        let files = listing(&["app", "../../etc/x", "/tmp/evil", ".."]);
+        let names = Get::local_names(&step, &files).unwrap();
+        assert_eq!(names, ["app", ".._.._etc_x", "_tmp_evil", "__"]);
+        for name in names.iter() {
+            assert!(validate_file_name(name).is_ok());
+        }
+    }
+
+    #[test]
+    fn local_names_rejects_sanitized_collisions() {
+        let step = GetStepParams {
+            sanitize_names: true,
+            ..Default::default()
+        };
+
+        let files = listing(&["a/b", "a_b"]);
+        assert!(Get::local_names(&step, &files).is_err());
+    }
+
+    #[test]
+    fn move_into_keeps_existing_files() {
+        let source = tempfile::tempdir().unwrap();
+        let destination = tempfile::tempdir().unwrap();
+        fs::write(source.path().join("app"), "extracted").unwrap();
+        fs::write(destination.path().join("app"), "downloaded").unwrap();
+
+        let mut moved = Vec::new();
+        assert!(move_into(source.path(), destination.path(), &mut moved).is_err());
+        assert_eq!(
+            fs::read_to_string(destination.path().join("app")).unwrap(),
+            "downloaded"
+        );
+        assert!(moved.is_empty());
+    }
+}
@@ -1,5 +1,8 @@
 use anyhow::Result;
 use clap::{Parser, Subcommand};
+use gen_api_wrapper::query::AsyncQuery;
+
+use crate::{client::GiteaClient, endpoints::PackageEndpoint, models::Package, params::Source};
 
 mod check;
 mod get;
@@ -35,3 +38,24 @@ impl Commands {
         }
     }
 }
+
+/// Look up a single package version for its metadata.
+///
+/// This is only used to describe the version to concourse, so failures are
+/// reported but otherwise ignored.
+async fn fetch_package(client: &GiteaClient, source: &Source, version: &str) -> Option<Package> {
+    let endpoint = PackageEndpoint::buidler()
+        .owner(&source.owner)
+        .package(&source.package)
+        .version(version)
+        .build()
+        .ok()?;
+
+    match endpoint.query_async(client).await {
+        Ok(package) => Some(package),
+        Err(e) => {
+            eprintln!("Could not fetch details for version {}: {}", version, e);
+            None
+        }
+    }
+}
@@ -1,17 +1,47 @@
-use std::{collections::HashSet, path::PathBuf};
+use std::{
+    collections::{BTreeMap, HashMap, HashSet},
+    fs::File,
+    io,
+    path::{Path, PathBuf},
+    sync::Mutex,
+};
 
-use anyhow::{anyhow, bail, Result};
+use anyhow::{anyhow, bail, Context, Result};
 use clap::Args;
 use clap_stdin::FileOrStdin;
-use gen_api_wrapper::query::AsyncQuery;
+use ed25519_dalek::SigningKey;
+use futures_util::{stream, TryStreamExt};
+use gen_api_wrapper::{error::ApiError, query::AsyncQuery};
+use http::StatusCode;
+use regex::Regex;
+use sha2::{Digest, Sha256};
 
+use super::fetch_package;
 use crate::{
-    client::GiteaClient,
-    endpoints::{PackageFilesEndpoint, PackageUploadEndpoint},
-    models::PackageFile,
-    params::{OutOutput, OutParams, Version},
+    client::{GiteaClient, GiteaError},
+    endpoints::{
+        PackageDeleteEndpoint, PackageFileDeleteEndpoint, PackageFilesEndpoint,
+        PackageUploadEndpoint,
+    },
+    models::{files_digest, PackageFile},
+    names::validate_file_name,
+    params::{
+        ChecksumFormat, MetadataField, OnConflict, OutFile, OutOutput, OutParams, OutStepParams,
+        Version,
+    },
+    signing::{self, signature_name, Statement, SIGNATURE_EXTENSION},
 };
 
+/// What a publish has done so far.
+#[derive(Debug, Default)]
+struct Published {
+    /// The names of the files we uploaded, in case we need to roll back.
+    uploaded: Vec<String>,
+    /// The sha256 of each file we know the content of, whether we uploaded it
+    /// or it already existed.
+    digests: BTreeMap<String, String>,
+}
+
 #[derive(Debug, Clone, Args)]
 pub struct Out {
     sources: PathBuf,
@@ -27,67 +57,865 @@ impl Out {
             bail!("Must specify at least one file to upload");
         }
 
+        let signing_key = params
+            .source
+            .signing_key
+            .as_deref()
+            .map(signing::parse_signing_key)
+            .transpose()?;
+
+        let version = self.resolve_version(&params.params)?;
+        eprintln!("Publishing version {}", &version);
+
         // see if we have files that already exist for the specified version
-        let endpoint = PackageFilesEndpoint::buidler()
+        let files_endpoint = PackageFilesEndpoint::buidler()
             .owner(&params.source.owner)
             .package(&params.source.package)
-            .version(&params.params.version)
+            .version(&version)
             .build()?;
 
         // TODO: This could fail for just connectivity reasons, but gitea will
         // prevent the overwrite anyway - MCL - 2023-07-30
-        let existing_files: Vec<PackageFile> =
-            endpoint.query_async(&client).await.ok().unwrap_or_default();
-
-        let existing_names: HashSet<&String> =
-            HashSet::from_iter(existing_files.iter().map(|f| &f.name));
-
-        for file in self.params.params.files.iter() {
-            let filename = file
-                .file_name()
-                .ok_or_else(|| {
-                    anyhow!(
-                        "Could not determine basename for {}",
-                        file.to_string_lossy()
+        //
+        // Only a 404 tells us the version doesn't exist yet, which is what
+        // lets a rollback remove the whole version.
+        let (existing_files, created): (Vec<PackageFile>, bool) =
+            match files_endpoint.query_async(&client).await {
+                Ok(files) => (files, false),
+                Err(ApiError::ServerService { status, .. }) if status == StatusCode::NOT_FOUND => {
+                    (Vec::new(), true)
+                }
+                Err(e) => {
+                    eprintln!("Could not list existing files: {}", e);
+                    (Vec::new(), false)
+                }
+            };
+
+        let existing: HashMap<&str, &PackageFile> =
+            HashMap::from_iter(existing_files.iter().map(|f| (f.name.as_str(), f)));
+
+        eprintln!(
+            "Existing files will be handled with on_conflict: {}",
+            params.params.on_conflict
+        );
+
+        // Figure out everything we need to upload before we upload anything,
+        // so a bad path doesn't leave us with a partially published version.
+        let mut uploads: Vec<(String, PathBuf, bool)> = Vec::new();
+        // the sha256 of every file we know the content of
+        let mut digests: BTreeMap<String, String> = BTreeMap::new();
+        for (filename, file) in self.resolve_files(&params.params)? {
+            if params.source.completion_marker.as_ref() == Some(&filename) {
+                bail!(
+                    "'{}' would be uploaded as '{}', which is the completion marker",
+                    file.to_string_lossy(),
+                    filename
+                );
+            }
+
+            if params.params.checksums_file() == Some(filename.as_str()) {
+                bail!(
+                    "'{}' would be uploaded as '{}', which is the checksum manifest",
+                    file.to_string_lossy(),
+                    filename
+                );
+            }
+
+            let exists = match existing.get(filename.as_str()) {
+                None => false,
+                Some(_) if params.params.on_conflict == OnConflict::Skip => {
+                    eprintln!(
+                        "Skipping '{}' because it already exists for version {}",
+                        filename, &version
+                    );
+                    continue;
+                }
+                Some(_) if params.params.on_conflict == OnConflict::Fail => bail!(
+                    "'{}' already exists for version {}, refusing to continue because on_conflict is fail",
+                    filename,
+                    &version
+                ),
+                Some(remote) => {
+                    let local = sha256_file(&file).with_context(|| {
+                        format!("Could not hash '{}'", file.to_string_lossy())
+                    })?;
+                    let remote = remote.sha256.as_deref().unwrap_or("unknown");
+
+                    if remote.eq_ignore_ascii_case(&local) {
+                        eprintln!(
+                            "Skipping '{}' because it already exists for version {} with the same content",
+                            filename, &version
+                        );
+                        digests.insert(filename, local);
+                        continue;
+                    }
+
+                    if params.params.on_conflict == OnConflict::Verify {
+                        bail!(
+                            "'{}' already exists for version {} with different content: local sha256 {}, remote sha256 {}",
+                            filename,
+                            &version,
+                            local,
+                            remote
+                        );
+                    }
+
+                    eprintln!(
+                        "'{}' differs from the existing file: local sha256 {}, remote sha256 {}",
+                        filename, local, remote
+                    );
+                    true
+                }
+            };
+
+            uploads.push((filename, file, exists));
+        }
+
+        if signing_key.is_some() {
+            Self::check_signature_names(&params, &uploads, &digests)?;
+        }
+
+        let published = Mutex::new(Published {
+            uploaded: Vec::new(),
+            digests,
+        });
+        let result = Self::publish(
+            &client,
+            &params,
+            &version,
+            &uploads,
+            &existing,
+            signing_key.as_ref(),
+            &published,
+        )
+        .await;
+
+        if let Err(e) = result {
+            let uploaded = published.into_inner().unwrap().uploaded;
+            if !params.params.rollback_on_failure {
+                eprintln!(
+                    "Not rolling back {} uploaded file(s) because rollback_on_failure is off",
+                    uploaded.len()
+                );
+            } else if !uploaded.is_empty() {
+                Self::rollback(&client, &params, &version, created, &uploaded).await;
+            }
+
+            return Err(e);
+        }
+
+        eprintln!("Finished uploading files");
+
+        // see what the version looks like now that we're done
+        let published: Vec<PackageFile> = match files_endpoint.query_async(&client).await {
+            Ok(files) => files,
+            Err(e) if !params.source.track_files => {
+                eprintln!("Could not list uploaded files: {}", e);
+                Vec::new()
+            }
+            Err(e) => return Err(e).context("Could not list uploaded files"),
+        };
+
+        let digest = params.source.track_files.then(|| files_digest(&published));
+
+        let version = Version { version, digest };
+        let package = fetch_package(&client, &params.source, &version.version).await;
+        let output = OutOutput::from(&version)
+            .with_metadata(MetadataField::describe(package.as_ref(), &published));
+        println!("{}", serde_json::to_string(&output)?);
+
+        Ok(())
+    }
+
+    /// Upload the files, then their signatures, then the checksum manifest,
+    /// then the completion marker, recording what we've uploaded as we go.
+    async fn publish(
+        client: &GiteaClient,
+        params: &OutParams,
+        version: &str,
+        uploads: &[(String, PathBuf, bool)],
+        existing: &HashMap<&str, &PackageFile>,
+        signing_key: Option<&SigningKey>,
+        published: &Mutex<Published>,
+    ) -> Result<()> {
+        // upload everything, stopping as soon as one of them fails
+        stream::iter(uploads.iter().map(Ok))
+            .try_for_each_concurrent(params.source.concurrency(), |(filename, target, exists)| {
+                async move {
+                    // gitea won't let us upload over an existing file
+                    if *exists {
+                        eprintln!(
+                            "Overwriting '{}', deleting the existing file for version {}",
+                            filename, version
+                        );
+
+                        delete_file(client, params, version, filename)
+                            .await
+                            .with_context(|| format!("Failed deleting existing '{}'", filename))?;
+                    }
+
+                    eprintln!("Uploading {}", filename);
+
+                    let endpoint = PackageUploadEndpoint::buidler()
+                        .owner(&params.source.owner)
+                        .package(&params.source.package)
+                        .version(version)
+                        .file(filename)
+                        .build()?;
+
+                    // Record the file before sending it, since the server may
+                    // finish the upload even if we stop waiting for it.
+                    published.lock().unwrap().uploaded.push(filename.clone());
+
+                    let digest = match client.upload(target, &endpoint).await {
+                        Ok(digest) => digest,
+                        Err(e) => {
+                            let ours = match e.downcast_ref::<GiteaError>() {
+                                // a conflict on the first attempt means the
+                                // file is someone else's
+                                Some(GiteaError::Conflict { .. }) => {
+                                    published.lock().unwrap().uploaded.retain(|n| n != filename);
+                                    None
+                                }
+                                // but after a retry it may be ours, if an
+                                // earlier attempt went through after all
+                                Some(GiteaError::RetriedConflict { .. }) => {
+                                    Self::earlier_upload(client, params, version, filename, target)
+                                        .await
+                                }
+                                _ => None,
+                            };
+
+                            match ours {
+                                Some(digest) => digest,
+                                None => {
+                                    return Err(e).with_context(|| {
+                                        format!("Failed uploading '{}'", filename)
+                                    })
+                                }
+                            }
+                        }
+                    };
+
+                    published
+                        .lock()
+                        .unwrap()
+                        .digests
+                        .insert(filename.clone(), digest);
+                    eprintln!("Uploaded {}", filename);
+                    Ok::<_, anyhow::Error>(())
+                }
+            })
+            .await?;
+
+        // Sign everything we know the content of, which includes files we
+        // skipped because they were identical, in case they were published
+        // before we were signing things.
+        if let Some(key) = signing_key {
+            let digests = published.lock().unwrap().digests.clone();
+            stream::iter(digests.iter().map(Ok))
+                .try_for_each_concurrent(params.source.concurrency(), |(name, digest)| async move {
+                    let statement = Statement {
+                        package: &params.source.package,
+                        version,
+                        file: name,
+                        sha256: digest,
+                    };
+                    let signature = signing::sign(key, &statement)?;
+                    Self::upload_generated(
+                        client,
+                        params,
+                        version,
+                        existing,
+                        published,
+                        &signature_name(name),
+                        signature,
                     )
-                })?
-                .to_string_lossy();
+                    .await
+                })
+                .await?;
+        }
+
+        if let (Some(format), Some(name)) =
+            (params.params.checksums, params.params.checksums_file())
+        {
+            let digests = manifest_digests(params, existing, &published.lock().unwrap().digests);
+            let manifest = render_manifest(format, &digests)?;
+
+            let digest = hex::encode(Sha256::digest(&manifest));
+            let statement = Statement {
+                package: &params.source.package,
+                version,
+                file: name,
+                sha256: &digest,
+            };
+            let signature = signing_key
+                .map(|key| signing::sign(key, &statement))
+                .transpose()?;
+
+            Self::upload_generated(client, params, version, existing, published, name, manifest)
+                .await?;
 
-            if existing_names.contains(&filename.to_string()) {
+            if let Some(signature) = signature {
+                Self::upload_generated(
+                    client,
+                    params,
+                    version,
+                    existing,
+                    published,
+                    &signature_name(name),
+                    signature,
+                )
+                .await?;
+            }
+        }
+
+        // The marker goes last so that check only sees the version once
+        // everything else has been uploaded.
+        if let Some(ref marker) = params.source.completion_marker {
+            if existing.contains_key(marker.as_str()) {
                 eprintln!(
-                    "Skipping '{}' because it already exists for version {}",
-                    filename, &self.params.params.version
+                    "Skipping completion marker '{}' because it already exists for version {}",
+                    marker, version
                 );
-                continue;
+            } else {
+                eprintln!("Uploading completion marker {}", marker);
+
+                let endpoint = PackageUploadEndpoint::buidler()
+                    .owner(&params.source.owner)
+                    .package(&params.source.package)
+                    .version(version)
+                    .file(marker)
+                    .build()?;
+
+                published.lock().unwrap().uploaded.push(marker.clone());
+                client.upload_bytes(Vec::new(), &endpoint).await?;
             }
+        }
+
+        Ok(())
+    }
 
-            eprintln!("Uploading {}", filename);
+    /// Check whether an earlier attempt at uploading `target` went through,
+    /// returning its sha256 if the remote `filename` has the same content.
+    ///
+    /// Anything else, including failing to check, leaves the file on the
+    /// rollback list, since we can't tell whose it is.
+    async fn earlier_upload(
+        client: &GiteaClient,
+        params: &OutParams,
+        version: &str,
+        filename: &str,
+        target: &Path,
+    ) -> Option<String> {
+        let check = async {
+            let local = sha256_file(target)
+                .with_context(|| format!("Could not hash '{}'", target.to_string_lossy()))?;
 
-            let endpoint = PackageUploadEndpoint::buidler()
+            let endpoint = PackageFilesEndpoint::buidler()
                 .owner(&params.source.owner)
                 .package(&params.source.package)
-                .version(&params.params.version)
-                .file(filename)
+                .version(version)
                 .build()?;
+            let files: Vec<PackageFile> = endpoint.query_async(client).await?;
+
+            let matches = files.iter().any(|f| {
+                f.name == filename
+                    && f.sha256
+                        .as_deref()
+                        .is_some_and(|remote| remote.eq_ignore_ascii_case(&local))
+            });
+
+            Ok::<_, anyhow::Error>(matches.then_some(local))
+        };
+
+        match check.await {
+            Ok(Some(digest)) => {
+                eprintln!(
+                    "'{}' already exists with the same content, an earlier attempt must have succeeded",
+                    filename
+                );
+                Some(digest)
+            }
+            Ok(None) => None,
+            Err(e) => {
+                eprintln!(
+                    "Could not check whether '{}' was uploaded by an earlier attempt: {:#}",
+                    filename, e
+                );
+                None
+            }
+        }
+    }
+
+    /// Upload a file we generated, such as the checksum manifest or a
+    /// signature, applying `on_conflict` if it already exists.
+    async fn upload_generated(
+        client: &GiteaClient,
+        params: &OutParams,
+        version: &str,
+        existing: &HashMap<&str, &PackageFile>,
+        published: &Mutex<Published>,
+        name: &str,
+        data: Vec<u8>,
+    ) -> Result<()> {
+        let local = hex::encode(Sha256::digest(&data));
+
+        let replace = match existing.get(name) {
+            None => false,
+            Some(remote)
+                if remote
+                    .sha256
+                    .as_deref()
+                    .is_some_and(|r| r.eq_ignore_ascii_case(&local)) =>
+            {
+                eprintln!(
+                    "Skipping '{}' because it already exists for version {} with the same content",
+                    name, version
+                );
+                return Ok(());
+            }
+            // the manifest describes the whole version, so it has to be
+            // replaced whenever the version changes
+            Some(remote) if is_manifest(params, name) => {
+                eprintln!(
+                    "Replacing '{}' since the version has changed: local sha256 {}, remote sha256 {}",
+                    name,
+                    local,
+                    remote.sha256.as_deref().unwrap_or("unknown")
+                );
+                true
+            }
+            Some(_) if params.params.on_conflict == OnConflict::Skip => {
+                eprintln!(
+                    "Skipping '{}' because it already exists for version {}",
+                    name, version
+                );
+                return Ok(());
+            }
+            Some(remote) if params.params.on_conflict == OnConflict::Overwrite => {
+                eprintln!(
+                    "Overwriting '{}': local sha256 {}, remote sha256 {}",
+                    name,
+                    local,
+                    remote.sha256.as_deref().unwrap_or("unknown")
+                );
+                true
+            }
+            Some(remote) => bail!(
+                "'{}' already exists for version {} with different content: local sha256 {}, remote sha256 {}",
+                name,
+                version,
+                local,
+                remote.sha256.as_deref().unwrap_or("unknown")
+            ),
+        };
+
+        if replace {
+            delete_file(client, params, version, name)
+                .await
+                .with_context(|| format!("Failed deleting existing '{}'", name))?;
+        }
 
-            let target = self.sources.join(file);
-            if !target.is_file() {
+        eprintln!("Uploading {}", name);
+
+        let endpoint = PackageUploadEndpoint::buidler()
+            .owner(&params.source.owner)
+            .package(&params.source.package)
+            .version(version)
+            .file(name)
+            .build()?;
+
+        published.lock().unwrap().uploaded.push(name.to_string());
+        client.upload_bytes(data, &endpoint).await?;
+
+        Ok(())
+    }
+
+    /// Ensure no signature we publish would have the same name as another file
+    /// we publish.
+    fn check_signature_names(
+        params: &OutParams,
+        uploads: &[(String, PathBuf, bool)],
+        digests: &BTreeMap<String, String>,
+    ) -> Result<()> {
+        let mut names: HashSet<&str> = uploads.iter().map(|(name, _, _)| name.as_str()).collect();
+        names.extend(digests.keys().map(|name| name.as_str()));
+        names.extend(params.params.checksums_file());
+        names.extend(params.source.completion_marker.as_deref());
+
+        let signed = uploads
+            .iter()
+            .map(|(name, _, _)| name.as_str())
+            .chain(digests.keys().map(|name| name.as_str()))
+            .chain(params.params.checksums_file());
+
+        for name in signed {
+            let signature = signature_name(name);
+            if names.contains(signature.as_str()) {
                 bail!(
-                    "File '{}' is not a file or does not exist",
-                    file.to_string_lossy()
+                    "The signature for '{}' would be uploaded as '{}', which is already the name of another file",
+                    name,
+                    signature
                 );
             }
+        }
+
+        Ok(())
+    }
+
+    /// Remove what a failed publish uploaded, or the whole version if it
+    /// didn't exist before we started and nothing else has been uploaded to it
+    /// since.
+    ///
+    /// Failures are only reported, since we're already failing the step.
+    async fn rollback(
+        client: &GiteaClient,
+        params: &OutParams,
+        version: &str,
+        created: bool,
+        uploaded: &[String],
+    ) {
+        // Another put may be publishing to the same new version, so only
+        // remove the version if everything in it is ours.
+        if created {
+            let listing = match PackageFilesEndpoint::buidler()
+                .owner(&params.source.owner)
+                .package(&params.source.package)
+                .version(version)
+                .build()
+            {
+                Ok(endpoint) => endpoint.query_async(client).await,
+                Err(e) => {
+                    eprintln!("Could not list version {}: {}", version, e);
+                    return;
+                }
+            };
+
+            let remaining: Vec<PackageFile> = match listing {
+                Ok(files) => files,
+                Err(ApiError::ServerService { status, .. }) if status == StatusCode::NOT_FOUND => {
+                    eprintln!(
+                        "Rolled back: version {} no longer exists, nothing to remove",
+                        version
+                    );
+                    return;
+                }
+                Err(e) => {
+                    eprintln!(
+                        "Could not list version {}: {}, removing uploaded files only",
+                        version, e
+                    );
+                    Vec::new()
+                }
+            };
+
+            if !remaining.is_empty() && remaining.iter().all(|f| uploaded.contains(&f.name)) {
+                eprintln!("Rolling back: removing version {}", version);
+                match delete_version(client, params, version).await {
+                    Ok(()) => eprintln!("Rolled back: removed version {}", version),
+                    Err(e) => eprintln!("Failed to remove version {}: {}", version, e),
+                }
+
+                return;
+            }
 
-            client.upload(&target, &endpoint).await?;
+            if remaining.iter().any(|f| !uploaded.contains(&f.name)) {
+                eprintln!(
+                    "Not removing version {} because it contains files we didn't upload",
+                    version
+                );
+            }
         }
 
-        eprintln!("Finished uploading files");
+        eprintln!(
+            "Rolling back: removing {} uploaded file(s) from version {}",
+            uploaded.len(),
+            version
+        );
+
+        for name in uploaded {
+            match delete_file(client, params, version, name).await {
+                Ok(true) => eprintln!("Rolled back: removed '{}'", name),
+                Ok(false) => eprintln!("Rolled back: '{}' was never created", name),
+                Err(e) => eprintln!("Failed to remove '{}': {}", name, e),
+            }
+        }
+    }
 
-        let version = Version {
-            version: self.params.params.version.clone(),
+    /// Determine the version to publish from either `version` or
+    /// `version_file`, applying the prefix and suffix and validating the
+    /// result.
+    fn resolve_version(&self, step: &OutStepParams) -> Result<String> {
+        let base = match (&step.version, &step.version_file) {
+            (Some(version), None) => version.clone(),
+            (None, Some(file)) => {
+                let path = self.sources.join(file);
+                std::fs::read_to_string(&path)
+                    .with_context(|| {
+                        format!("Could not read version file '{}'", file.to_string_lossy())
+                    })?
+                    .trim()
+                    .to_string()
+            }
+            (Some(_), Some(_)) => bail!("Only one of version and version_file may be specified"),
+            (None, None) => bail!("Must specify one of version or version_file"),
         };
-        println!("{}", serde_json::to_string(&OutOutput::from(&version))?);
 
-        Ok(())
+        let version = format!(
+            "{}{}{}",
+            step.version_prefix.as_deref().unwrap_or_default(),
+            base,
+            step.version_suffix.as_deref().unwrap_or_default()
+        );
+
+        if version.is_empty() {
+            bail!("Version must not be empty");
+        }
+
+        if let Some(ref pattern) = step.version_regex {
+            let re = Regex::new(&format!("^(?:{})$", pattern)).context("Invalid version_regex")?;
+            if !re.is_match(&version) {
+                bail!(
+                    "Version '{}' does not match version_regex '{}'",
+                    version,
+                    pattern
+                );
+            }
+        }
+
+        Ok(version)
+    }
+
+    /// Expand the `files` param into the name and path of each file to upload.
+    ///
+    /// Entries may be literal paths, glob patterns, or directories, all
+    /// relative to the sources directory. A directory contributes the files
+    /// directly inside it. Files are uploaded under their basename unless the
+    /// entry gives a name, and no two files may be uploaded under the same
+    /// name.
+    fn resolve_files(&self, step: &OutStepParams) -> Result<Vec<(String, PathBuf)>> {
+        let mut resolved: Vec<(String, PathBuf)> = Vec::new();
+        let mut names: HashMap<String, PathBuf> = HashMap::new();
+        let mut add = |name: Option<&str>, path: PathBuf| -> Result<()> {
+            let name = match name {
+                Some(name) => name.to_string(),
+                None => path
+                    .file_name()
+                    .ok_or_else(|| {
+                        anyhow!(
+                            "Could not determine basename for {}",
+                            path.to_string_lossy()
+                        )
+                    })?
+                    .to_string_lossy()
+                    .to_string(),
+            };
+
+            match names.get(&name) {
+                // the same file listed twice is harmless
+                Some(other) if *other == path => {}
+                Some(other) => bail!(
+                    "Both '{}' and '{}' would be uploaded as '{}'",
+                    other.to_string_lossy(),
+                    path.to_string_lossy(),
+                    name
+                ),
+                None => {
+                    names.insert(name.clone(), path.clone());
+                    resolved.push((name, path));
+                }
+            }
+
+            Ok(())
+        };
+
+        for entry in step.files.iter() {
+            let entry_str = entry.path().to_string_lossy();
+
+            if let OutFile::Renamed { path, name } = entry {
+                validate_file_name(name)?;
+
+                let target = self.sources.join(path);
+                if !target.is_file() {
+                    bail!("File '{}' is not a file or does not exist", &entry_str);
+                }
+
+                add(Some(name), target)?;
+                continue;
+            }
+
+            if !is_glob(&entry_str) {
+                let target = self.sources.join(entry.path());
+                if target.is_dir() {
+                    let mut contents = std::fs::read_dir(&target)
+                        .with_context(|| format!("Could not read directory '{}'", &entry_str))?
+                        .map(|e| e.map(|e| e.path()))
+                        .collect::<Result<Vec<_>, _>>()?;
+                    contents.retain(|p| p.is_file());
+                    contents.sort();
+
+                    if contents.is_empty() {
+                        bail!("Directory '{}' does not contain any files", &entry_str);
+                    }
+
+                    for path in contents {
+                        add(None, path)?;
+                    }
+                } else if target.is_file() {
+                    add(None, target)?;
+                } else {
+                    bail!("File '{}' is not a file or does not exist", &entry_str);
+                }
+
+                continue;
+            }
+
+            let pattern = format!(
+                "{}/{}",
+                glob::Pattern::escape(&self.sources.to_string_lossy()),
+                entry_str
+            );
+
+            let mut matched = 0;
+            for path in
+                glob::glob(&pattern).with_context(|| format!("Invalid pattern '{}'", &entry_str))?
+            {
+                let path = path?;
+                if path.is_file() {
+                    matched += 1;
+                    add(None, path)?;
+                }
+            }
+
+            if matched == 0 {
+                bail!("Pattern '{}' did not match any files", &entry_str);
+            }
+
+            if let Some(expect) = step.expect {
+                if matched > expect {
+                    bail!(
+                        "Pattern '{}' matched {} files, but at most {} were expected",
+                        &entry_str,
+                        matched,
+                        expect
+                    );
+                }
+            }
+        }
+
+        Ok(resolved)
     }
 }
+
+fn is_glob(entry: &str) -> bool {
+    entry.contains(['*', '?', '['])
+}
+
+fn sha256_file(path: &Path) -> io::Result<String> {
+    let mut file = File::open(path)?;
+    let mut hasher = Sha256::new();
+    io::copy(&mut file, &mut hasher)?;
+
+    Ok(hex::encode(hasher.finalize()))
+}
+
+/// Delete a file from the version, returning whether it existed.
+async fn delete_file(
+    client: &GiteaClient,
+    params: &OutParams,
+    version: &str,
+    name: &str,
+) -> Result<bool> {
+    let endpoint = PackageFileDeleteEndpoint::buidler()
+        .owner(&params.source.owner)
+        .package(&params.source.package)
+        .version(version)
+        .file(name)
+        .build()?;
+
+    match gen_api_wrapper::raw(endpoint).query_async(client).await {
+        Ok(_) => Ok(true),
+        Err(ApiError::ServerService { status, .. }) if status == StatusCode::NOT_FOUND => Ok(false),
+        Err(e) => Err(e.into()),
+    }
+}
+
+async fn delete_version(client: &GiteaClient, params: &OutParams, version: &str) -> Result<()> {
+    let endpoint = PackageDeleteEndpoint::buidler()
+        .owner(&params.source.owner)
+        .package(&params.source.package)
+        .version(version)
+        .build()?;
+
+    gen_api_wrapper::raw(endpoint).query_async(client).await?;
+
+    Ok(())
+}
+
+/// Whether `name` is the checksum manifest or its signature.
+fn is_manifest(params: &OutParams, name: &str) -> bool {
+    params
+        .params
+        .checksums_file()
+        .is_some_and(|manifest| name == manifest || name == signature_name(manifest))
+}
+
+/// The digests to list in the checksum manifest: every file in the version,
+/// using what this put knows about the files it handled and what the server
+/// reported for the rest.
+///
+/// The files we generate (the manifest, signatures and the completion marker)
+/// are left out.
+fn manifest_digests(
+    params: &OutParams,
+    existing: &HashMap<&str, &PackageFile>,
+    digests: &BTreeMap<String, String>,
+) -> BTreeMap<String, String> {
+    let is_signature = |name: &str| {
+        name.strip_suffix(SIGNATURE_EXTENSION)
+            .is_some_and(|signed| digests.contains_key(signed) || existing.contains_key(signed))
+    };
+
+    let mut manifest = digests.clone();
+    for (name, file) in existing {
+        if manifest.contains_key(*name)
+            || is_manifest(params, name)
+            || is_signature(name)
+            || params.source.completion_marker.as_deref() == Some(*name)
+        {
+            continue;
+        }
+
+        match file.sha256 {
+            Some(ref sha256) => {
+                manifest.insert(name.to_string(), sha256.to_ascii_lowercase());
+            }
+            None => eprintln!(
+                "'{}' will not be in the checksum manifest since its sha256 is unknown",
+                name
+            ),
+        }
+    }
+
+    manifest
+}
+
+/// Render the checksum manifest for the given file digests.
+fn render_manifest(format: ChecksumFormat, digests: &BTreeMap<String, String>) -> Result<Vec<u8>> {
+    let manifest = match format {
+        ChecksumFormat::Gnu => digests
+            .iter()
+            .map(|(name, digest)| format!("{}  {}\n", digest, name))
+            .collect::<String>()
+            .into_bytes(),
+        ChecksumFormat::Bsd => digests
+            .iter()
+            .map(|(name, digest)| format!("SHA256 ({}) = {}\n", name, digest))
+            .collect::<String>()
+            .into_bytes(),
+        ChecksumFormat::Json => serde_json::to_vec_pretty(digests)?,
+    };
+
+    Ok(manifest)
+}
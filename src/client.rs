@@ -1,9 +1,14 @@
-use std::{fs::File, io::Write, path::Path};
+use std::{
+    fs::File,
+    io::{Seek, Write},
+    path::{Path, PathBuf},
+    sync::{Arc, Mutex},
+};
 
 use anyhow::{Context, Result};
 use async_trait::async_trait;
 use bytes::Bytes;
-use futures_util::StreamExt;
+use futures_util::{StreamExt, TryStreamExt};
 use gen_api_wrapper::{
     client::{AsyncClient, RestClient},
     endpoint_prelude::Endpoint,
@@ -14,14 +19,20 @@ use http::{
     header::{self, AUTHORIZATION},
     HeaderMap, HeaderValue, Request, Response,
 };
-use reqwest::{Body, Client};
+use reqwest::{Body, Client, StatusCode};
+use serde::{de::DeserializeOwned, Deserialize};
+use sha2::{Digest, Sha256};
+use tempfile::NamedTempFile;
 use thiserror::Error;
 use tokio_util::codec::{BytesCodec, FramedRead};
 use url::Url;
 
 use crate::{
     endpoints::{PackageFileEndpoint, PackageUploadEndpoint},
+    models::PackageFile,
+    names::validate_file_name,
     params::Source,
+    retry::{is_retryable_error, is_retryable_status, RetryPolicy},
 };
 
 #[derive(Debug, Error)]
@@ -46,6 +57,104 @@ pub enum GiteaError {
         #[from]
         source: ApiError<RestError>,
     },
+    #[error("http error: {}", source)]
+    Http {
+        #[from]
+        source: http::Error,
+    },
+    #[error("io error: {}", source)]
+    Io {
+        #[from]
+        source: std::io::Error,
+    },
+    #[error("authentication failed ({}): {}", status, message)]
+    Unauthorized { status: StatusCode, message: String },
+    #[error("not found: {}", message)]
+    NotFound { message: String },
+    #[error("conflict: {}", message)]
+    Conflict { message: String },
+    /// A conflict on an upload we had already tried, which may have been
+    /// caused by an earlier attempt that went through after all.
+    #[error("conflict after retrying: {}", message)]
+    RetriedConflict { message: String },
+    #[error("payload too large: {}", message)]
+    TooLarge { message: String },
+    #[error("rate limited: {}", message)]
+    RateLimited { message: String },
+    #[error("gitea server error ({}): {}", status, message)]
+    Server { status: StatusCode, message: String },
+    #[error("unexpected response ({}): {}", status, message)]
+    UnexpectedStatus { status: StatusCode, message: String },
+    #[error(
+        "size mismatch for '{}': expected {} bytes, got {} bytes",
+        file,
+        expected,
+        actual
+    )]
+    SizeMismatch {
+        file: String,
+        expected: u64,
+        actual: u64,
+    },
+    #[error(
+        "sha256 mismatch for '{}': expected {}, got {}",
+        file,
+        expected,
+        actual
+    )]
+    ChecksumMismatch {
+        file: String,
+        expected: String,
+        actual: String,
+    },
+}
+
+impl GiteaError {
+    /// Determine if the operation that produced this error is worth retrying.
+    pub fn is_retryable(&self) -> bool {
+        match self {
+            Self::Communication { source } => is_retryable_error(source),
+            Self::RateLimited { .. } | Self::Server { .. } => true,
+            _ => false,
+        }
+    }
+
+    /// Map an unsuccessful response to the matching error, pulling the message
+    /// out of gitea's error body when there is one.
+    fn from_status(status: StatusCode, body: &[u8]) -> Self {
+        #[derive(Deserialize)]
+        struct ErrorBody {
+            message: String,
+        }
+
+        let message = match serde_json::from_slice::<ErrorBody>(body) {
+            Ok(b) => b.message,
+            Err(_) => String::from_utf8_lossy(body).trim().to_string(),
+        };
+
+        match status {
+            StatusCode::UNAUTHORIZED | StatusCode::FORBIDDEN => {
+                Self::Unauthorized { status, message }
+            }
+            StatusCode::NOT_FOUND => Self::NotFound { message },
+            StatusCode::CONFLICT => Self::Conflict { message },
+            StatusCode::PAYLOAD_TOO_LARGE => Self::TooLarge { message },
+            StatusCode::TOO_MANY_REQUESTS => Self::RateLimited { message },
+            s if s.is_server_error() => Self::Server { status, message },
+            _ => Self::UnexpectedStatus { status, message },
+        }
+    }
+}
+
+/// Turn an unsuccessful response into a [GiteaError].
+async fn error_for_status(rsp: reqwest::Response) -> Result<reqwest::Response, GiteaError> {
+    let status = rsp.status();
+    if status.is_success() {
+        return Ok(rsp);
+    }
+
+    let body = rsp.bytes().await?;
+    Err(GiteaError::from_status(status, &body))
 }
 
 #[derive(Debug, Error)]
@@ -76,6 +185,63 @@ pub enum AuthError {
     },
 }
 
+/// A single page of results from a paginated gitea endpoint.
+#[derive(Debug, Clone)]
+pub struct Page<T> {
+    pub items: Vec<T>,
+    /// The value of the `X-Total-Count` header, if the server sent one.
+    pub total: Option<u64>,
+    /// Whether the `Link` header had a `rel="next"` entry, if the server sent
+    /// a `Link` header at all.
+    pub link_next: Option<bool>,
+}
+
+impl<T> Page<T> {
+    /// Build a page from its items and the pagination headers of the response
+    /// they came from.
+    fn from_headers(items: Vec<T>, headers: &HeaderMap) -> Self {
+        let total = headers
+            .get("x-total-count")
+            .and_then(|v| v.to_str().ok())
+            .and_then(|v| v.trim().parse().ok());
+        let link_next = headers
+            .get(header::LINK)
+            .and_then(|v| v.to_str().ok())
+            .map(|v| {
+                v.split(',')
+                    .any(|link| link.split(';').skip(1).any(|p| p.trim() == r#"rel="next""#))
+            });
+
+        Self {
+            items,
+            total,
+            link_next,
+        }
+    }
+
+    /// Determine if there is another page after this one.
+    ///
+    /// `fetched` is the number of items fetched so far, including this page,
+    /// and `limit` is the page size that was requested. We prefer the `Link`
+    /// header, then fall back to the total count, then to checking if this
+    /// page was full.
+    pub fn has_next(&self, fetched: u64, limit: u64) -> bool {
+        if self.items.is_empty() {
+            return false;
+        }
+
+        if let Some(next) = self.link_next {
+            return next;
+        }
+
+        if let Some(total) = self.total {
+            return fetched < total;
+        }
+
+        self.items.len() as u64 >= limit
+    }
+}
+
 #[derive(Clone)]
 struct Auth {
     token: String,
@@ -101,6 +267,7 @@ pub struct GiteaClient {
     client: Client,
     uri: Url,
     auth: Auth,
+    retry: RetryPolicy,
 }
 
 impl GiteaClient {
@@ -116,14 +283,72 @@ impl GiteaClient {
             auth: Auth {
                 token: token.into(),
             },
+            retry: RetryPolicy::default(),
         })
     }
 
+    /// Use the given [RetryPolicy] for all requests.
+    pub fn with_retry(mut self, retry: RetryPolicy) -> Self {
+        self.retry = retry;
+        self
+    }
+
+    /// Query a single page of a paginated endpoint.
+    ///
+    /// This is like [AsyncQuery](gen_api_wrapper::query::AsyncQuery), except
+    /// that we hang on to the pagination headers gitea sends back.
+    pub async fn query_page<E, T>(&self, endpoint: &E) -> Result<Page<T>, ApiError<RestError>>
+    where
+        E: Endpoint + Sync,
+        T: DeserializeOwned,
+    {
+        let mut url = self.rest_endpoint(&endpoint.endpoint())?;
+        endpoint.parameters().add_to_url(&mut url);
+
+        let req = Request::builder()
+            .method(endpoint.method())
+            .uri(query::url_to_http_uri(url));
+        let (req, data) = if let Some((mime, data)) = endpoint.body()? {
+            let req = req.header(header::CONTENT_TYPE, mime);
+            (req, data)
+        } else {
+            (req, Vec::new())
+        };
+        let rsp = self.rest_async(req, data).await?;
+        let status = rsp.status();
+        if !status.is_success() {
+            return Err(ApiError::ServerService {
+                status,
+                data: rsp.body().to_vec(),
+            });
+        }
+
+        let items = serde_json::from_slice(rsp.body()).map_err(|source| ApiError::DataType {
+            source,
+            typename: std::any::type_name::<Vec<T>>(),
+        })?;
+
+        Ok(Page::from_headers(items, rsp.headers()))
+    }
+
+    /// Download a file into `destination` as `name`, verifying it against the
+    /// size and sha256 gitea published for it.
+    ///
+    /// The file is written to a temporary file in `destination` and only
+    /// renamed into place once it's complete and verified, so a failed
+    /// download never leaves a truncated file behind. Returns the path of the
+    /// downloaded file and its sha256.
     pub async fn download<'a>(
         &self,
         destination: &Path,
+        name: &str,
         endpoint: &PackageFileEndpoint<'a>,
-    ) -> Result<()> {
+        expected: &PackageFile,
+    ) -> Result<(PathBuf, String)> {
+        // this should have already been checked, but never write outside of
+        // the destination
+        validate_file_name(name)?;
+
         // we're just going to do this directly so we can get at the body as bytes
         // TODO: it would be nice if the gen wrapper handled this
         // - MCL - 2023-07-29
@@ -140,29 +365,167 @@ impl GiteaClient {
             (req, Vec::new())
         };
         let http_request = req.body(data)?;
-        let request = http_request.try_into()?;
-        let rsp = self.client.execute(request).await?;
+        let request: reqwest::Request = http_request.try_into()?;
 
         // we're going to do this in chunks
-        let target = destination.join(endpoint.file.to_string());
-        let mut file = File::create(&target)
-            .with_context(|| format!("Failed to create file '{}'", &target.to_string_lossy()))?;
+        let target = destination.join(name);
+        let mut tmp = part_file(destination, name)?;
+
+        let mut hasher = Sha256::new();
+        let mut size: u64 = 0;
+        let mut attempt = 0;
+
+        loop {
+            match self
+                .download_attempt(&request, tmp.as_file_mut(), &mut hasher, &mut size)
+                .await
+            {
+                Ok(()) => break,
+                Err(e) if e.is_retryable() && self.retry.should_retry(attempt) => {
+                    attempt += 1;
+                    eprintln!(
+                        "Downloading '{}' failed: {}, retrying ({}/{})",
+                        &endpoint.file, e, attempt, self.retry.retries
+                    );
+                    self.retry.wait(attempt).await;
+                }
+                Err(e) => return Err(e.into()),
+            }
+        }
+
+        if let Some(expected_size) = expected.size {
+            if expected_size != size {
+                return Err(GiteaError::SizeMismatch {
+                    file: expected.name.clone(),
+                    expected: expected_size,
+                    actual: size,
+                }
+                .into());
+            }
+        }
+
+        let actual = hex::encode(hasher.finalize());
+        if let Some(ref expected_sha256) = expected.sha256 {
+            if !expected_sha256.eq_ignore_ascii_case(&actual) {
+                return Err(GiteaError::ChecksumMismatch {
+                    file: expected.name.clone(),
+                    expected: expected_sha256.clone(),
+                    actual,
+                }
+                .into());
+            }
+        }
+
+        tmp.as_file()
+            .sync_all()
+            .context("Failed to sync downloaded file")?;
+        tmp.persist(&target).with_context(|| {
+            format!(
+                "Failed to move downloaded file to '{}'",
+                target.to_string_lossy()
+            )
+        })?;
+
+        Ok((target, actual))
+    }
+
+    /// Make a single attempt at downloading into `file`.
+    ///
+    /// If a previous attempt got part of the way, we ask the server for just
+    /// the rest of the file, starting over if it doesn't support that.
+    async fn download_attempt(
+        &self,
+        request: &reqwest::Request,
+        file: &mut File,
+        hasher: &mut Sha256,
+        size: &mut u64,
+    ) -> Result<(), GiteaError> {
+        let mut request = request
+            .try_clone()
+            .expect("download requests do not have streaming bodies");
+
+        if *size > 0 {
+            let range = HeaderValue::from_str(&format!("bytes={}-", size))
+                .expect("range header is always valid");
+            request.headers_mut().insert(header::RANGE, range);
+        }
+
+        let rsp = self.client.execute(request).await?;
+        let rsp = error_for_status(rsp).await?;
+
+        if *size > 0 && rsp.status() != StatusCode::PARTIAL_CONTENT {
+            eprintln!("Server does not support resuming downloads, starting over");
+            file.set_len(0)?;
+            file.rewind()?;
+            *hasher = Sha256::new();
+            *size = 0;
+        }
 
         let mut stream = rsp.bytes_stream();
 
         while let Some(item) = stream.next().await {
             let chunk = item?;
+            hasher.update(&chunk);
+            *size += chunk.len() as u64;
             file.write_all(&chunk)?;
         }
 
         Ok(())
     }
 
+    /// Upload a file, returning the sha256 of what was sent.
     pub async fn upload<'a>(
         &self,
         target: &Path,
         endpoint: &PackageUploadEndpoint<'a>,
-    ) -> Result<()> {
+    ) -> Result<String> {
+        self.upload_from(UploadSource::File(target), endpoint)
+            .await
+            .with_context(|| format!("Failed to upload file '{}'", target.to_string_lossy()))
+    }
+
+    /// Upload in-memory contents as a file, for files we generate ourselves.
+    pub async fn upload_bytes<'a>(
+        &self,
+        data: Vec<u8>,
+        endpoint: &PackageUploadEndpoint<'a>,
+    ) -> Result<String> {
+        self.upload_from(UploadSource::Bytes(&data), endpoint)
+            .await
+            .with_context(|| format!("Failed to upload file '{}'", &endpoint.file))
+    }
+
+    async fn upload_from<'a>(
+        &self,
+        source: UploadSource<'_>,
+        endpoint: &PackageUploadEndpoint<'a>,
+    ) -> Result<String, GiteaError> {
+        let mut attempt = 0;
+
+        loop {
+            match self.upload_attempt(&source, endpoint).await {
+                Ok(digest) => return Ok(digest),
+                Err(e) if e.is_retryable() && self.retry.should_retry(attempt) => {
+                    attempt += 1;
+                    eprintln!(
+                        "Uploading '{}' failed: {}, retrying ({}/{})",
+                        &endpoint.file, e, attempt, self.retry.retries
+                    );
+                    self.retry.wait(attempt).await;
+                }
+                Err(GiteaError::Conflict { message }) if attempt > 0 => {
+                    return Err(GiteaError::RetriedConflict { message })
+                }
+                Err(e) => return Err(e),
+            }
+        }
+    }
+
+    async fn upload_attempt<'a>(
+        &self,
+        source: &UploadSource<'_>,
+        endpoint: &PackageUploadEndpoint<'a>,
+    ) -> Result<String, GiteaError> {
         // we're just going to do this directly.
         // TODO: it would be nice if the gen wrapper handled this
         // - MCL - 2023-07-29
@@ -173,21 +536,60 @@ impl GiteaClient {
             .uri(query::url_to_http_uri(url));
         self.auth.set_header(req.headers_mut().unwrap())?;
 
-        let file = tokio::fs::File::open(target)
-            .await
-            .with_context(|| format!("Could not open file: '{}'", target.to_string_lossy()))?;
-
-        let stream = FramedRead::new(file, BytesCodec::new());
-        let body = Body::wrap_stream(stream);
-
+        // hash the body as it goes out, so we don't read the file twice
+        let hasher = Arc::new(Mutex::new(Sha256::new()));
+        let body = source.body(hasher.clone()).await?;
         let http_request = req.body(body)?;
         let request = http_request.try_into()?;
-        self.client
-            .execute(request)
-            .await
-            .with_context(|| format!("Failed to upload file '{}'", target.to_string_lossy()))?;
+        let rsp = self.client.execute(request).await?;
+        error_for_status(rsp).await?;
 
-        Ok(())
+        let hasher = std::mem::take(&mut *hasher.lock().unwrap());
+        Ok(hex::encode(hasher.finalize()))
+    }
+
+    /// Execute a request, buffering the whole response.
+    async fn execute_buffered(
+        &self,
+        request: reqwest::Request,
+    ) -> Result<Response<Bytes>, RestError> {
+        let rsp = self.client.execute(request).await?;
+
+        let mut http_rsp = Response::builder()
+            .status(rsp.status())
+            .version(rsp.version());
+        let headers = http_rsp.headers_mut().unwrap();
+        for (key, value) in rsp.headers() {
+            headers.insert(key, value.clone());
+        }
+        Ok(http_rsp.body(rsp.bytes().await?)?)
+    }
+}
+
+/// Where the contents of an upload come from.
+///
+/// Bodies can't be reused between attempts, so we make a new one each time.
+enum UploadSource<'a> {
+    File(&'a Path),
+    Bytes(&'a [u8]),
+}
+
+impl<'a> UploadSource<'a> {
+    /// Build a body for the contents, feeding them into `hasher` as they're
+    /// sent.
+    async fn body(&self, hasher: Arc<Mutex<Sha256>>) -> Result<Body, GiteaError> {
+        match self {
+            Self::File(path) => {
+                let file = tokio::fs::File::open(path).await?;
+                let stream = FramedRead::new(file, BytesCodec::new())
+                    .inspect_ok(move |chunk| hasher.lock().unwrap().update(chunk));
+                Ok(Body::wrap_stream(stream))
+            }
+            Self::Bytes(data) => {
+                hasher.lock().unwrap().update(data);
+                Ok(Body::from(data.to_vec()))
+            }
+        }
     }
 }
 
@@ -195,7 +597,7 @@ impl TryFrom<&Source> for GiteaClient {
     type Error = GiteaError;
 
     fn try_from(value: &Source) -> std::result::Result<Self, Self::Error> {
-        Self::new(value.uri.clone(), &value.token)
+        Ok(Self::new(value.uri.clone(), &value.token)?.with_retry(value.retry_policy()))
     }
 }
 
@@ -218,18 +620,165 @@ impl AsyncClient for GiteaClient {
         let call = || async {
             self.auth.set_header(request.headers_mut().unwrap())?;
             let http_request = request.body(body)?;
-            let request = http_request.try_into()?;
-            let rsp = self.client.execute(request).await?;
-
-            let mut http_rsp = Response::builder()
-                .status(rsp.status())
-                .version(rsp.version());
-            let headers = http_rsp.headers_mut().unwrap();
-            for (key, value) in rsp.headers() {
-                headers.insert(key, value.clone());
+            let request: reqwest::Request = http_request.try_into()?;
+            let mut attempt = 0;
+
+            loop {
+                let result = self
+                    .execute_buffered(
+                        request
+                            .try_clone()
+                            .expect("api requests do not have streaming bodies"),
+                    )
+                    .await;
+
+                let reason = match result {
+                    Ok(ref rsp) if is_retryable_status(rsp.status()) => {
+                        Some(rsp.status().to_string())
+                    }
+                    Err(RestError::Communication { ref source }) if is_retryable_error(source) => {
+                        Some(source.to_string())
+                    }
+                    _ => None,
+                };
+
+                match reason {
+                    Some(reason) if self.retry.should_retry(attempt) => {
+                        attempt += 1;
+                        eprintln!(
+                            "Request to '{}' failed: {}, retrying ({}/{})",
+                            request.url(),
+                            reason,
+                            attempt,
+                            self.retry.retries
+                        );
+                        self.retry.wait(attempt).await;
+                    }
+                    _ => return result,
+                }
             }
-            Ok(http_rsp.body(rsp.bytes().await?)?)
         };
         call().map_err(ApiError::client).await
     }
 }
+
+/// Create the temporary file a download is written to before it's renamed
+/// into place.
+///
+/// tempfile creates its files with mode 0600, but the downloaded file should
+/// end up with the same permissions as any other file we create (0644 minus
+/// the umask).
+fn part_file(destination: &Path, name: &str) -> Result<NamedTempFile> {
+    let prefix = format!(".{}.", name);
+    let mut builder = tempfile::Builder::new();
+    builder.prefix(&prefix).suffix(".part");
+    #[cfg(unix)]
+    builder.permissions(std::os::unix::fs::PermissionsExt::from_mode(0o644));
+
+    builder.tempfile_in(destination).with_context(|| {
+        format!(
+            "Failed to create temporary file in '{}'",
+            destination.to_string_lossy()
+        )
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn page(items: usize, headers: &[(&'static str, &'static str)]) -> Page<u64> {
+        let mut map = HeaderMap::new();
+        for (name, value) in headers {
+            map.insert(*name, HeaderValue::from_static(value));
+        }
+
+        Page::from_headers((0..items as u64).collect(), &map)
+    }
+
+    #[test]
+    fn link_with_next_has_next() {
+        let page = page(
+            2,
+            &[(
+                "link",
+                r#"<https://gitea/api/v1/packages/o?page=2&limit=2>; rel="next",<https://gitea/api/v1/packages/o?page=5&limit=2>; rel="last""#,
+            )],
+        );
+
+        assert_eq!(page.link_next, Some(true));
+        assert!(page.has_next(2, 2));
+    }
+
+    #[test]
+    fn link_without_next_is_last_page() {
+        // even a full page with a total saying otherwise defers to the link
+        let page = page(
+            2,
+            &[
+                (
+                    "link",
+                    r#"<https://gitea/api/v1/packages/o?page=1&limit=2>; rel="first",<https://gitea/api/v1/packages/o?page=4&limit=2>; rel="prev""#,
+                ),
+                ("x-total-count", "100"),
+            ],
+        );
+
+        assert_eq!(page.link_next, Some(false));
+        assert!(!page.has_next(2, 2));
+    }
+
+    #[test]
+    fn missing_link_falls_back_to_total_count() {
+        let page = page(2, &[("x-total-count", "5")]);
+
+        assert_eq!(page.link_next, None);
+        assert_eq!(page.total, Some(5));
+        assert!(page.has_next(4, 2));
+        assert!(!page.has_next(5, 2));
+    }
+
+    #[test]
+    fn missing_headers_fall_back_to_full_page() {
+        assert!(page(2, &[]).has_next(2, 2));
+        assert!(!page(1, &[]).has_next(3, 2));
+    }
+
+    #[test]
+    fn empty_page_is_last_page() {
+        let page = page(
+            0,
+            &[
+                (
+                    "link",
+                    r#"<https://gitea/api/v1/packages/o?page=2&limit=2>; rel="next""#,
+                ),
+                ("x-total-count", "10"),
+            ],
+        );
+
+        assert!(!page.has_next(0, 2));
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn downloaded_file_is_not_private() {
+        use std::os::unix::fs::PermissionsExt;
+
+        let dir = tempfile::tempdir().unwrap();
+        let mode = |path: &Path| std::fs::metadata(path).unwrap().permissions().mode() & 0o777;
+
+        // a plain file gets 0666 minus the umask, so this is 0644 minus it
+        let plain = dir.path().join("plain");
+        File::create(&plain).unwrap();
+        let expected = mode(&plain) & 0o644;
+
+        let target = dir.path().join("app");
+        part_file(dir.path(), "app")
+            .unwrap()
+            .persist(&target)
+            .unwrap();
+
+        assert_eq!(mode(&target), expected);
+    }
+}
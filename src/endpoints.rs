@@ -11,6 +11,12 @@ pub struct PackagesEndpoint<'a> {
 
     #[builder(setter(into))]
     package: Cow<'a, str>,
+
+    #[builder(setter(strip_option), default)]
+    page: Option<u64>,
+
+    #[builder(setter(strip_option), default)]
+    limit: Option<u64>,
 }
 
 impl<'a> Endpoint for PackagesEndpoint<'a> {
@@ -22,11 +28,13 @@ impl<'a> Endpoint for PackagesEndpoint<'a> {
         format!("api/v1/packages/{}", self.owner).into()
     }
 
-    fn parameters(&self) -> QueryParams {
+    fn parameters(&self) -> QueryParams<'_> {
         let mut params = QueryParams::default();
         // TODO: maybe make configurable - MCL - 2023-07-29
         params.push("type", "generic");
         params.push("q", &self.package);
+        params.push_opt("page", self.page);
+        params.push_opt("limit", self.limit);
         params
     }
 }
@@ -37,6 +45,39 @@ impl<'a> PackagesEndpoint<'a> {
     }
 }
 
+#[derive(Debug, Clone, Builder)]
+pub struct PackageEndpoint<'a> {
+    #[builder(setter(into))]
+    owner: Cow<'a, str>,
+
+    #[builder(setter(into))]
+    package: Cow<'a, str>,
+
+    #[builder(setter(into))]
+    version: Cow<'a, str>,
+}
+
+impl<'a> Endpoint for PackageEndpoint<'a> {
+    fn method(&self) -> Method {
+        Method::GET
+    }
+
+    fn endpoint(&self) -> std::borrow::Cow<'static, str> {
+        // TODO: make 'generic' configurable - MCL - 2023-07-29
+        format!(
+            "api/v1/packages/{}/generic/{}/{}",
+            self.owner, self.package, self.version,
+        )
+        .into()
+    }
+}
+
+impl<'a> PackageEndpoint<'a> {
+    pub fn buidler() -> PackageEndpointBuilder<'a> {
+        PackageEndpointBuilder::default()
+    }
+}
+
 #[derive(Debug, Clone, Builder)]
 pub struct PackageFilesEndpoint<'a> {
     #[builder(setter(into))]
@@ -141,3 +182,72 @@ impl<'a> PackageUploadEndpoint<'a> {
         PackageUploadEndpointBuilder::default()
     }
 }
+
+#[derive(Debug, Clone, Builder)]
+pub struct PackageFileDeleteEndpoint<'a> {
+    #[builder(setter(into))]
+    owner: Cow<'a, str>,
+
+    #[builder(setter(into))]
+    package: Cow<'a, str>,
+
+    #[builder(setter(into))]
+    version: Cow<'a, str>,
+
+    #[builder(setter(into))]
+    pub file: Cow<'a, str>,
+}
+
+impl<'a> Endpoint for PackageFileDeleteEndpoint<'a> {
+    fn method(&self) -> Method {
+        Method::DELETE
+    }
+
+    fn endpoint(&self) -> std::borrow::Cow<'static, str> {
+        // TODO: make 'generic' configurable - MCL - 2023-07-29
+        format!(
+            "api/packages/{}/generic/{}/{}/{}",
+            self.owner, self.package, self.version, self.file,
+        )
+        .into()
+    }
+}
+
+impl<'a> PackageFileDeleteEndpoint<'a> {
+    pub fn buidler() -> PackageFileDeleteEndpointBuilder<'a> {
+        PackageFileDeleteEndpointBuilder::default()
+    }
+}
+
+#[derive(Debug, Clone, Builder)]
+pub struct PackageDeleteEndpoint<'a> {
+    #[builder(setter(into))]
+    owner: Cow<'a, str>,
+
+    #[builder(setter(into))]
+    package: Cow<'a, str>,
+
+    #[builder(setter(into))]
+    version: Cow<'a, str>,
+}
+
+impl<'a> Endpoint for PackageDeleteEndpoint<'a> {
+    fn method(&self) -> Method {
+        Method::DELETE
+    }
+
+    fn endpoint(&self) -> std::borrow::Cow<'static, str> {
+        // TODO: make 'generic' configurable - MCL - 2023-07-29
+        format!(
+            "api/v1/packages/{}/generic/{}/{}",
+            self.owner, self.package, self.version,
+        )
+        .into()
+    }
+}
+
+impl<'a> PackageDeleteEndpoint<'a> {
+    pub fn buidler() -> PackageDeleteEndpointBuilder<'a> {
+        PackageDeleteEndpointBuilder::default()
+    }
+}
@@ -1,10 +1,14 @@
 use anyhow::Result;
 
+mod archive;
 mod cli;
 mod client;
 mod endpoints;
 mod models;
+mod names;
 mod params;
+mod retry;
+mod signing;
 
 #[tokio::main]
 async fn main() -> Result<()> {
@@ -1,13 +1,60 @@
 use serde::Deserialize;
+use sha2::{Digest, Sha256};
 
 #[derive(Debug, Clone, Eq, PartialEq, Deserialize)]
 pub struct Package {
     pub id: u64,
     pub version: String,
     pub name: String,
+
+    #[serde(default)]
+    pub html_url: Option<String>,
+
+    #[serde(default)]
+    pub creator: Option<User>,
+
+    #[serde(default)]
+    pub created_at: Option<String>,
+}
+
+#[derive(Debug, Clone, Eq, PartialEq, Deserialize)]
+pub struct User {
+    pub login: String,
 }
 
 #[derive(Debug, Clone, Eq, PartialEq, Deserialize)]
 pub struct PackageFile {
     pub name: String,
+
+    // gitea doesn't give this one a json tag, so it's capitalized
+    #[serde(default, rename = "Size", alias = "size")]
+    pub size: Option<u64>,
+
+    #[serde(default)]
+    pub md5: Option<String>,
+
+    #[serde(default)]
+    pub sha1: Option<String>,
+
+    #[serde(default)]
+    pub sha256: Option<String>,
+
+    #[serde(default)]
+    pub sha512: Option<String>,
+}
+
+/// Compute a digest identifying the set of files in a version.
+///
+/// This is independent of the order gitea lists the files in.
+pub fn files_digest(files: &[PackageFile]) -> String {
+    let mut names: Vec<&str> = files.iter().map(|f| f.name.as_str()).collect();
+    names.sort_unstable();
+
+    let mut hasher = Sha256::new();
+    for name in names {
+        hasher.update(name.as_bytes());
+        hasher.update(b"\n");
+    }
+
+    hex::encode(hasher.finalize())
 }
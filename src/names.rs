@@ -0,0 +1,108 @@
+use std::path::{Component, Path};
+
+use thiserror::Error;
+
+#[derive(Debug, Error)]
+#[error("refusing to use unsafe file name '{}': {}", name, reason)]
+pub struct UnsafeFileName {
+    pub name: String,
+    pub reason: &'static str,
+}
+
+/// Ensure a server-supplied file name is safe to join onto a local directory.
+///
+/// The name must be a single, normal path component: no separators, no `.` or
+/// `..`, and nothing absolute.
+pub fn validate_file_name(name: &str) -> Result<(), UnsafeFileName> {
+    let unsafe_name = |reason| {
+        Err(UnsafeFileName {
+            name: name.to_string(),
+            reason,
+        })
+    };
+
+    if name.is_empty() {
+        return unsafe_name("it is empty");
+    }
+
+    if name.contains(['/', '\\']) {
+        return unsafe_name("it contains a path separator");
+    }
+
+    if name.contains('\0') {
+        return unsafe_name("it contains a null byte");
+    }
+
+    let mut components = Path::new(name).components();
+    match (components.next(), components.next()) {
+        (Some(Component::Normal(c)), None) if c == name => Ok(()),
+        (Some(Component::CurDir | Component::ParentDir), _) => {
+            unsafe_name("it refers to a directory")
+        }
+        _ => unsafe_name("it is not a plain file name"),
+    }
+}
+
+/// Map a server-supplied file name to a safe equivalent.
+///
+/// Separators and null bytes become `_`, and names made up entirely of dots
+/// have them replaced, so the result always passes [validate_file_name] unless
+/// the name was empty.
+pub fn sanitize_file_name(name: &str) -> String {
+    let mapped: String = name
+        .chars()
+        .map(|c| match c {
+            '/' | '\\' | '\0' => '_',
+            c => c,
+        })
+        .collect();
+
+    if !mapped.is_empty() && mapped.chars().all(|c| c == '.') {
+        return "_".repeat(mapped.len());
+    }
+
+    mapped
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn rejects_hostile_names() {
+        for name in ["../../etc/x", "/tmp/evil", "a\\b", ".", "..", "", "a\0b"] {
+            assert!(
+                validate_file_name(name).is_err(),
+                "'{}' should be rejected",
+                name.escape_debug()
+            );
+        }
+    }
+
+    #[test]
+    fn accepts_plain_names() {
+        assert!(validate_file_name("app-1.2.3.tar.gz").is_ok());
+    }
+
+    #[test]
+    fn sanitized_names_are_valid() {
+        for name in [
+            "../../etc/x",
+            "/tmp/evil",
+            "a\\b",
+            ".",
+            "..",
+            "...",
+            "a\0b",
+            "app-1.2.3.tar.gz",
+        ] {
+            let sanitized = sanitize_file_name(name);
+            assert!(
+                validate_file_name(&sanitized).is_ok(),
+                "'{}' sanitized to invalid '{}'",
+                name.escape_debug(),
+                sanitized.escape_debug()
+            );
+        }
+    }
+}
@@ -1,24 +1,170 @@
-use std::{path::PathBuf, str::FromStr};
+use std::{path::PathBuf, str::FromStr, time::Duration};
 
 use anyhow::Context;
+use semver::VersionReq;
 use serde::{Deserialize, Serialize};
 use url::Url;
 
+use crate::{
+    models::{Package, PackageFile},
+    retry::RetryPolicy,
+};
+
 #[derive(Debug, Clone, Eq, PartialEq, Deserialize)]
 pub struct Source {
     pub uri: Url,
     pub owner: String,
     pub token: String,
     pub package: String,
+
+    #[serde(default)]
+    pub order_by: VersionOrder,
+
+    #[serde(default)]
+    pub version_constraint: Option<VersionReq>,
+
+    #[serde(default)]
+    pub version_regex: Option<String>,
+
+    #[serde(default)]
+    pub ignore_versions: Vec<String>,
+
+    /// Emit the most recently emitted version again when its set of files
+    /// changes. Only that version is tracked: concourse only gives `check`
+    /// the last version it emitted, so changes to older versions go unseen.
+    #[serde(default)]
+    pub track_files: bool,
+
+    #[serde(default)]
+    pub required_files: Vec<String>,
+
+    #[serde(default)]
+    pub completion_marker: Option<String>,
+
+    #[serde(default)]
+    pub on_missing_version: MissingVersion,
+
+    #[serde(default)]
+    pub concurrency: Option<usize>,
+
+    #[serde(default)]
+    pub retries: Option<u32>,
+
+    #[serde(default)]
+    pub retry_backoff_ms: Option<u64>,
+
+    /// A base64-encoded ed25519 secret key `out` signs each file with.
+    #[serde(default)]
+    pub signing_key: Option<String>,
+
+    /// A base64-encoded ed25519 public key `in` verifies each file with.
+    #[serde(default)]
+    pub public_key: Option<String>,
+}
+
+impl Source {
+    /// The number of files to transfer at once. Defaults to one at a time.
+    pub fn concurrency(&self) -> usize {
+        self.concurrency.unwrap_or(1).max(1)
+    }
+
+    /// The [RetryPolicy] for requests, falling back to the defaults for
+    /// anything not specified.
+    pub fn retry_policy(&self) -> RetryPolicy {
+        let default = RetryPolicy::default();
+        RetryPolicy {
+            retries: self.retries.unwrap_or(default.retries),
+            backoff: self
+                .retry_backoff_ms
+                .map(Duration::from_millis)
+                .unwrap_or(default.backoff),
+        }
+    }
+}
+
+/// How `check` orders the versions it emits.
+#[derive(Debug, Clone, Copy, Default, Eq, PartialEq, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum VersionOrder {
+    /// Order by when the version was created in gitea.
+    #[default]
+    Created,
+    /// Order by semantic version, ignoring versions that do not parse.
+    Semver,
+}
+
+/// What `check` does when the version it was given no longer exists.
+#[derive(Debug, Clone, Copy, Default, Eq, PartialEq, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum MissingVersion {
+    /// Emit only the latest matching version.
+    #[default]
+    Latest,
+    /// Emit every matching version newer than the missing one. This is only
+    /// possible when ordering by semver, otherwise every matching version is
+    /// emitted.
+    Newer,
 }
 
 #[derive(Debug, Clone, Eq, PartialEq, Deserialize, Serialize)]
 pub struct Version {
     pub version: String,
+
+    /// A digest of the set of files in the version, only present when
+    /// `track_files` is enabled.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub digest: Option<String>,
+}
+
+#[derive(Debug, Clone, Default, Eq, PartialEq, Deserialize, Serialize)]
+pub struct GetStepParams {
+    /// Glob patterns for the files to download. Every file is downloaded if
+    /// this is empty.
+    #[serde(default)]
+    pub files: Vec<String>,
+
+    /// Glob patterns for files to skip, even if they match `files`.
+    #[serde(default)]
+    pub exclude: Vec<String>,
+
+    /// Map file names that aren't safe to write locally (e.g. `../foo`) to
+    /// safe equivalents instead of failing.
+    #[serde(default)]
+    pub sanitize_names: bool,
+
+    #[serde(default)]
+    pub unpack: Unpack,
+
+    /// Remove archives once they've been extracted.
+    #[serde(default)]
+    pub delete_archives: bool,
+
+    /// Write the version metadata files into `.metadata/` instead of the root
+    /// of the destination.
+    #[serde(default)]
+    pub metadata_subdir: bool,
+
+    /// Only confirm the version exists and write its metadata, without
+    /// downloading any files.
+    #[serde(default)]
+    pub skip_download: bool,
 }
 
+/// Which downloaded archives to extract.
 #[derive(Debug, Clone, Eq, PartialEq, Deserialize, Serialize)]
-pub struct GetStepParams {}
+#[serde(untagged)]
+pub enum Unpack {
+    /// `true` extracts every supported archive, `false` extracts nothing.
+    All(bool),
+    /// Extract the archives matching these glob patterns.
+    Matching(Vec<String>),
+}
+
+impl Default for Unpack {
+    fn default() -> Self {
+        Self::All(false)
+    }
+}
 
 #[derive(Debug, Clone, Eq, PartialEq, Deserialize)]
 pub struct CheckParams {
@@ -28,25 +174,103 @@ pub struct CheckParams {
     pub version: Option<Version>,
 }
 
+/// A single entry of the metadata concourse displays for a step.
+#[derive(Debug, Clone, Eq, PartialEq, Serialize)]
+pub struct MetadataField {
+    pub name: String,
+    pub value: String,
+}
+
+impl MetadataField {
+    pub fn new(name: impl Into<String>, value: impl Into<String>) -> Self {
+        Self {
+            name: name.into(),
+            value: value.into(),
+        }
+    }
+
+    /// Describe a package version and its files.
+    ///
+    /// The package is optional, since we'd rather show the files than nothing
+    /// if we couldn't look it up.
+    pub fn describe(package: Option<&Package>, files: &[PackageFile]) -> Vec<Self> {
+        let mut metadata = Vec::new();
+
+        if let Some(package) = package {
+            if let Some(ref url) = package.html_url {
+                metadata.push(Self::new("url", url));
+            }
+
+            if let Some(ref creator) = package.creator {
+                metadata.push(Self::new("created_by", &creator.login));
+            }
+
+            if let Some(ref created_at) = package.created_at {
+                metadata.push(Self::new("created_at", created_at));
+            }
+        }
+
+        for file in files {
+            let mut details = Vec::new();
+            if let Some(size) = file.size {
+                details.push(format!("{} bytes", size));
+            }
+            if let Some(ref sha256) = file.sha256 {
+                details.push(format!("sha256:{}", sha256));
+            }
+
+            metadata.push(Self::new(&file.name, details.join(", ")));
+        }
+
+        metadata
+    }
+}
+
 #[derive(Debug, Clone, Eq, PartialEq, Serialize)]
 pub struct GetOutput<'a> {
     pub version: &'a Version,
+
+    #[serde(skip_serializing_if = "Vec::is_empty")]
+    pub metadata: Vec<MetadataField>,
+}
+
+impl<'a> GetOutput<'a> {
+    pub fn with_metadata(mut self, metadata: Vec<MetadataField>) -> Self {
+        self.metadata = metadata;
+        self
+    }
 }
 
 impl<'a> From<&'a Version> for GetOutput<'a> {
     fn from(value: &'a Version) -> Self {
-        Self { version: value }
+        Self {
+            version: value,
+            metadata: Vec::new(),
+        }
     }
 }
 
 #[derive(Debug, Clone, Eq, PartialEq, Serialize)]
 pub struct OutOutput<'a> {
     pub version: &'a Version,
+
+    #[serde(skip_serializing_if = "Vec::is_empty")]
+    pub metadata: Vec<MetadataField>,
+}
+
+impl<'a> OutOutput<'a> {
+    pub fn with_metadata(mut self, metadata: Vec<MetadataField>) -> Self {
+        self.metadata = metadata;
+        self
+    }
 }
 
 impl<'a> From<&'a Version> for OutOutput<'a> {
     fn from(value: &'a Version) -> Self {
-        Self { version: value }
+        Self {
+            version: value,
+            metadata: Vec::new(),
+        }
     }
 }
 
@@ -63,7 +287,9 @@ pub struct GetParams {
     pub source: Source,
 
     pub version: Version,
-    // pub params: GetStepParams,
+
+    #[serde(default)]
+    pub params: GetStepParams,
 }
 
 impl FromStr for GetParams {
@@ -76,8 +302,130 @@ impl FromStr for GetParams {
 
 #[derive(Debug, Clone, Eq, PartialEq, Deserialize, Serialize)]
 pub struct OutStepParams {
-    pub version: String,
-    pub files: Vec<PathBuf>,
+    #[serde(default)]
+    pub version: Option<String>,
+
+    /// A file containing the version, relative to the sources directory.
+    #[serde(default)]
+    pub version_file: Option<PathBuf>,
+
+    #[serde(default)]
+    pub version_prefix: Option<String>,
+
+    #[serde(default)]
+    pub version_suffix: Option<String>,
+
+    /// A regex the final version must match in its entirety.
+    #[serde(default)]
+    pub version_regex: Option<String>,
+
+    pub files: Vec<OutFile>,
+
+    /// The most files any one glob pattern in `files` may match.
+    #[serde(default)]
+    pub expect: Option<usize>,
+
+    #[serde(default)]
+    pub on_conflict: OnConflict,
+
+    /// Remove what we uploaded if the put fails partway through.
+    #[serde(default = "default_rollback_on_failure")]
+    pub rollback_on_failure: bool,
+
+    /// Publish a manifest of the sha256 of each file in this format.
+    #[serde(default)]
+    pub checksums: Option<ChecksumFormat>,
+
+    /// The name of the checksum manifest, defaulting based on its format.
+    #[serde(default)]
+    pub checksums_file: Option<String>,
+}
+
+impl OutStepParams {
+    /// The name of the checksum manifest, if we're publishing one.
+    pub fn checksums_file(&self) -> Option<&str> {
+        let format = self.checksums?;
+        Some(
+            self.checksums_file
+                .as_deref()
+                .unwrap_or_else(|| format.default_file_name()),
+        )
+    }
+}
+
+/// The format of the checksum manifest `out` publishes.
+#[derive(Debug, Clone, Copy, Eq, PartialEq, Deserialize, Serialize)]
+#[serde(rename_all = "snake_case")]
+pub enum ChecksumFormat {
+    /// `<sha256>  <name>` lines, as written by `sha256sum`.
+    Gnu,
+    /// `SHA256 (<name>) = <sha256>` lines, as written by BSD `sha256` and
+    /// `sha256sum --tag`.
+    Bsd,
+    /// A JSON object mapping each name to its sha256.
+    Json,
+}
+
+impl ChecksumFormat {
+    pub fn default_file_name(&self) -> &'static str {
+        match self {
+            Self::Gnu | Self::Bsd => "SHA256SUMS",
+            Self::Json => "SHA256SUMS.json",
+        }
+    }
+}
+
+fn default_rollback_on_failure() -> bool {
+    true
+}
+
+/// An entry in the `files` param of `out`.
+#[derive(Debug, Clone, Eq, PartialEq, Deserialize, Serialize)]
+#[serde(untagged)]
+pub enum OutFile {
+    /// A path, glob pattern, or directory, uploaded under the basename of each
+    /// file.
+    Path(PathBuf),
+    /// A single file, uploaded under the given name.
+    Renamed { path: PathBuf, name: String },
+}
+
+impl OutFile {
+    pub fn path(&self) -> &PathBuf {
+        match self {
+            Self::Path(path) => path,
+            Self::Renamed { path, .. } => path,
+        }
+    }
+}
+
+/// What `out` does with a file that already exists in the version.
+#[derive(Debug, Clone, Copy, Default, Eq, PartialEq, Deserialize, Serialize)]
+#[serde(rename_all = "snake_case")]
+pub enum OnConflict {
+    /// Skip the file if its content is identical to ours, otherwise fail the
+    /// put before uploading anything.
+    #[default]
+    Verify,
+    /// Leave the existing file alone and don't upload ours, regardless of its
+    /// content.
+    Skip,
+    /// Fail the put before uploading anything.
+    Fail,
+    /// Delete the existing file and upload ours in its place, unless its
+    /// content is identical to ours.
+    Overwrite,
+}
+
+impl std::fmt::Display for OnConflict {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Self::Verify => write!(f, "verify"),
+            Self::Skip => write!(f, "skip"),
+            Self::Fail => write!(f, "fail"),
+            Self::Overwrite => write!(f, "overwrite"),
+        }
+    }
 }
 
 #[derive(Debug, Clone, Eq, PartialEq, Deserialize)]
@@ -0,0 +1,55 @@
+use std::time::Duration;
+
+use rand::Rng;
+use reqwest::StatusCode;
+
+/// The longest we'll ever wait between attempts.
+const MAX_BACKOFF: Duration = Duration::from_secs(30);
+
+/// How many times, and how patiently, we retry failed requests.
+#[derive(Debug, Clone, Copy, Eq, PartialEq)]
+pub struct RetryPolicy {
+    /// The number of retries after the initial attempt.
+    pub retries: u32,
+    /// The delay before the first retry, doubled for each retry after that.
+    pub backoff: Duration,
+}
+
+impl Default for RetryPolicy {
+    fn default() -> Self {
+        Self {
+            retries: 3,
+            backoff: Duration::from_millis(500),
+        }
+    }
+}
+
+impl RetryPolicy {
+    /// Determine if we're allowed another retry after `attempt` retries.
+    pub fn should_retry(&self, attempt: u32) -> bool {
+        attempt < self.retries
+    }
+
+    /// Wait before making the given retry (starting from 1).
+    ///
+    /// The delay grows exponentially and half of it is randomized, so that
+    /// concurrent transfers don't all retry at the same moment.
+    pub async fn wait(&self, attempt: u32) {
+        let exp = attempt.saturating_sub(1).min(16);
+        let delay = self.backoff.saturating_mul(1 << exp).min(MAX_BACKOFF);
+        let half = delay / 2;
+        let jitter = rand::thread_rng().gen_range(Duration::ZERO..=half);
+
+        tokio::time::sleep(half + jitter).await;
+    }
+}
+
+/// Determine if a transport error is worth retrying.
+pub fn is_retryable_error(err: &reqwest::Error) -> bool {
+    err.is_connect() || err.is_timeout() || err.is_request() || err.is_body() || err.is_decode()
+}
+
+/// Determine if a response status is worth retrying.
+pub fn is_retryable_status(status: StatusCode) -> bool {
+    status.is_server_error() || status == StatusCode::TOO_MANY_REQUESTS
+}
@@ -0,0 +1,173 @@
+use base64::{engine::general_purpose::STANDARD, Engine};
+use ed25519_dalek::{Signature, Signer, SigningKey, Verifier, VerifyingKey};
+use thiserror::Error;
+
+/// The extension of the detached signature published alongside each file.
+pub const SIGNATURE_EXTENSION: &str = ".sig";
+
+#[derive(Debug, Error)]
+pub enum SigningError {
+    #[error("invalid {}: {}", kind, reason)]
+    InvalidKey { kind: &'static str, reason: String },
+
+    #[error("invalid signature for '{}': {}", file, reason)]
+    InvalidSignature { file: String, reason: String },
+
+    #[error(
+        "signature for '{}' does not match its contents, name, version and package",
+        file
+    )]
+    BadSignature { file: String },
+}
+
+/// The name of the signature for the file `name`.
+pub fn signature_name(name: &str) -> String {
+    format!("{}{}", name, SIGNATURE_EXTENSION)
+}
+
+/// Parse a base64-encoded ed25519 secret key (the 32 byte seed).
+pub fn parse_signing_key(key: &str) -> Result<SigningKey, SigningError> {
+    let bytes = decode_key(key, "signing_key")?;
+    Ok(SigningKey::from_bytes(&bytes))
+}
+
+/// Parse a base64-encoded ed25519 public key.
+pub fn parse_public_key(key: &str) -> Result<VerifyingKey, SigningError> {
+    let bytes = decode_key(key, "public_key")?;
+    VerifyingKey::from_bytes(&bytes).map_err(|e| SigningError::InvalidKey {
+        kind: "public_key",
+        reason: e.to_string(),
+    })
+}
+
+/// What a signature attests to: that a file with this content was published
+/// under this name, in this version of this package.
+#[derive(Debug, Clone, Copy)]
+pub struct Statement<'a> {
+    pub package: &'a str,
+    pub version: &'a str,
+    pub file: &'a str,
+    /// The hex-encoded sha256 of the file.
+    pub sha256: &'a str,
+}
+
+impl<'a> Statement<'a> {
+    /// The exact bytes that get signed.
+    ///
+    /// Each field is on its own line, so none of them may contain a newline.
+    pub fn message(&self) -> Result<Vec<u8>, SigningError> {
+        let fields = [self.package, self.version, self.file, self.sha256];
+        if fields.iter().any(|f| f.contains(['\n', '\r'])) {
+            return Err(SigningError::InvalidSignature {
+                file: self.file.to_string(),
+                reason: "the package, version and file name must not contain newlines".to_string(),
+            });
+        }
+
+        Ok(format!(
+            "gitea-package signature v1\npackage: {}\nversion: {}\nfile: {}\nsha256: {}\n",
+            self.package,
+            self.version,
+            self.file,
+            self.sha256.to_ascii_lowercase()
+        )
+        .into_bytes())
+    }
+}
+
+/// Sign a statement, producing the contents of its signature file.
+pub fn sign(key: &SigningKey, statement: &Statement) -> Result<Vec<u8>, SigningError> {
+    let signature = key.sign(&statement.message()?);
+
+    Ok(format!("{}\n", STANDARD.encode(signature.to_bytes())).into_bytes())
+}
+
+/// Verify the contents of a signature file against the statement it should
+/// attest to.
+pub fn verify(
+    key: &VerifyingKey,
+    statement: &Statement,
+    signature: &[u8],
+) -> Result<(), SigningError> {
+    let invalid = |reason: String| SigningError::InvalidSignature {
+        file: statement.file.to_string(),
+        reason,
+    };
+
+    let signature = std::str::from_utf8(signature).map_err(|e| invalid(e.to_string()))?;
+    let signature = STANDARD
+        .decode(signature.trim())
+        .map_err(|e| invalid(e.to_string()))?;
+    let signature = Signature::from_slice(&signature).map_err(|e| invalid(e.to_string()))?;
+
+    key.verify(&statement.message()?, &signature)
+        .map_err(|_| SigningError::BadSignature {
+            file: statement.file.to_string(),
+        })
+}
+
+fn decode_key(key: &str, kind: &'static str) -> Result<[u8; 32], SigningError> {
+    let invalid = |reason: String| SigningError::InvalidKey { kind, reason };
+
+    STANDARD
+        .decode(key.trim())
+        .map_err(|e| invalid(e.to_string()))?
+        .try_into()
+        .map_err(|b: Vec<u8>| invalid(format!("expected 32 bytes, got {}", b.len())))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const SHA256: &str = "98ea6e4f216f2fb4b69fff9b3a44842c38686ca685f3f55dc48c5d3fb1107be4";
+
+    fn statement<'a>(version: &'a str, file: &'a str) -> Statement<'a> {
+        Statement {
+            package: "app",
+            version,
+            file,
+            sha256: SHA256,
+        }
+    }
+
+    #[test]
+    fn message_matches_documented_format() {
+        let message = statement("1.2.3", "app").message().unwrap();
+        assert_eq!(
+            String::from_utf8(message).unwrap(),
+            format!(
+                "gitea-package signature v1\npackage: app\nversion: 1.2.3\nfile: app\nsha256: {}\n",
+                SHA256
+            )
+        );
+    }
+
+    #[test]
+    fn verifies_matching_statement() {
+        let key = SigningKey::from_bytes(&[7; 32]);
+        let signature = sign(&key, &statement("1.2.3", "app")).unwrap();
+
+        verify(&key.verifying_key(), &statement("1.2.3", "app"), &signature).unwrap();
+    }
+
+    #[test]
+    fn rejects_signature_from_another_version_or_file() {
+        let key = SigningKey::from_bytes(&[7; 32]);
+        let signature = sign(&key, &statement("1.2.2", "app")).unwrap();
+
+        assert!(verify(&key.verifying_key(), &statement("1.2.3", "app"), &signature).is_err());
+        assert!(verify(
+            &key.verifying_key(),
+            &statement("1.2.2", "other"),
+            &signature
+        )
+        .is_err());
+    }
+
+    #[test]
+    fn rejects_newlines_in_statement() {
+        let key = SigningKey::from_bytes(&[7; 32]);
+        assert!(sign(&key, &statement("1.2.3\nfile: x", "app")).is_err());
+    }
+}